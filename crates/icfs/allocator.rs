@@ -0,0 +1,353 @@
+// Hands out blocks of a `StableMemory` for objects of any size.
+//
+// Each block starts with a header and ends with a footer that both hold its
+// size, with the lowest bit set while it is allocated. The footer lets a block
+// that is being freed find the block before it, so that neighbouring free
+// blocks are always merged into one. Free blocks are kept in doubly linked
+// lists by size class, where class `i` holds the blocks of at least `2^(i+5)`
+// and less than `2^(i+6)` bytes, and the last class holds every block larger
+// than that. A free block at the end of the heap is given back to the heap
+// rather than put in a list, so the heap only grows when no free block fits.
+//
+// All of this lives in the memory itself, so the heap survives upgrades as it
+// is.
+//
+// Layout:
+// -------------------------------------------------- <- Byte 0
+// Magic "ICA"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Reserved space                         ↕ 4 bytes
+// End of the heap (in bytes)             ↕ 8 bytes
+// First free block of size class 0       ↕ 8 bytes
+// ...
+// First free block of size class 31      ↕ 8 bytes
+// -------------------------------------------------- <- Byte 272
+// Size of block 0 | allocated            ↕ 8 bytes
+// Bytes of block 0, or if it is free:
+//   Next free block in its size class    ↕ 8 bytes
+//   Previous free block in its class     ↕ 8 bytes
+//   Unused space                         ↕ N bytes
+// Size of block 0 | allocated            ↕ 8 bytes
+// -------------------------------------------------- <- Byte 272 + size of block 0
+// Block 1
+// ...
+// -------------------------------------------------- <- End of the heap
+use crate::error::Error;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::stable_memory::{read_at, write_at, StableMemory};
+
+const MAGIC: &[u8; 3] = b"ICA";
+const LAYOUT_VERSION: u8 = 1;
+
+const NUM_SIZE_CLASSES: usize = 32;
+
+const HEAP_END_OFFSET: u64 = 8;
+const FREE_LISTS_OFFSET: u64 = 16;
+const HEAP_START: u64 = FREE_LISTS_OFFSET + NUM_SIZE_CLASSES as u64 * 8;
+
+// Blocks are a multiple of this size, so that the lowest bit of their size is
+// free to mark them as allocated. It must be a power of two.
+const BLOCK_ALIGNMENT: u64 = 16;
+// The size of the header and the footer of a block together.
+const BLOCK_OVERHEAD: u64 = 16;
+// Large enough for the links of a free block between its header and footer.
+const MIN_BLOCK_SIZE: u64 = 32;
+
+const ALLOCATED: u64 = 1;
+// Marks the end of a free list.
+const NULL: u64 = 0;
+
+// The number of bytes copied at a time when moving an object.
+const COPY_CHUNK_SIZE_IN_BYTES: u64 = 4096;
+
+/// Allocates space for objects of varying sizes within a `StableMemory`, and
+/// hands out the addresses at which they can be read and written.
+///
+/// Addresses are offsets in the `StableMemory`, and stay valid across
+/// upgrades until they are freed. Clones share the same heap.
+#[derive(Clone, Debug)]
+pub struct Allocator<M: Memory = Ic0StableMemory> {
+    stable_memory: StableMemory<M>,
+}
+
+fn read_u64<M: Memory>(stable_memory: &StableMemory<M>, offset: u64) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    match read_at(stable_memory, offset, &mut bytes)? {
+        8 => Ok(u64::from_le_bytes(bytes)),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+fn write_u64<M: Memory>(stable_memory: &StableMemory<M>, offset: u64, value: u64) -> Result<(), Error> {
+    match write_at(stable_memory, offset, &value.to_le_bytes())? {
+        8 => Ok(()),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+/// Gets the size class of a free block of `size` bytes.
+fn size_class(size: u64) -> usize {
+    let log2 = 63 - size.leading_zeros() as usize;
+    log2.saturating_sub(5).min(NUM_SIZE_CLASSES - 1)
+}
+
+/// Gets the size of the block needed to hold an object of `len` bytes.
+fn block_size(len: u64) -> Result<u64, Error> {
+    let size = len
+        .checked_add(BLOCK_OVERHEAD + BLOCK_ALIGNMENT - 1)
+        .ok_or(Error::OffsetOverflow)?
+        / BLOCK_ALIGNMENT
+        * BLOCK_ALIGNMENT;
+    Ok(size.max(MIN_BLOCK_SIZE))
+}
+
+fn free_list_offset(class: usize) -> u64 {
+    FREE_LISTS_OFFSET + class as u64 * 8
+}
+
+impl<M: Memory> Allocator<M> {
+    /// Loads the heap stored in `stable_memory`, or creates a new one if
+    /// `stable_memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stable_memory` is not empty and does not hold a heap.
+    pub fn init(stable_memory: StableMemory<M>) -> Result<Self, Error> {
+        if stable_memory.is_empty() {
+            let mut header = [0; HEAP_START as usize];
+            header[0..3].copy_from_slice(MAGIC);
+            header[3] = LAYOUT_VERSION;
+            header[8..16].copy_from_slice(&HEAP_START.to_le_bytes());
+            if write_at(&stable_memory, 0, &header)? != header.len() {
+                return Err(Error::OutOfBounds);
+            }
+        } else {
+            let mut header = [0; 4];
+            read_at(&stable_memory, 0, &mut header)?;
+            assert_eq!(&header[0..3], MAGIC, "Memory does not hold a heap");
+            assert_eq!(header[3], LAYOUT_VERSION, "Unsupported heap layout version");
+        }
+        Ok(Self { stable_memory })
+    }
+
+    /// Returns a reference to the memory the heap lives in, through which
+    /// objects are read and written.
+    pub fn stable_memory(&self) -> &StableMemory<M> {
+        &self.stable_memory
+    }
+
+    /// Gets the offset at which the heap currently ends.
+    ///
+    /// The heap grows when no free block is large enough for an allocation,
+    /// and shrinks when the block at its end is freed.
+    pub fn heap_end(&self) -> Result<u64, Error> {
+        read_u64(&self.stable_memory, HEAP_END_OFFSET)
+    }
+
+    /// Allocates space for an object of `len` bytes and returns its address.
+    ///
+    /// The space is not zeroed.
+    pub fn alloc(&self, len: u64) -> Result<u64, Error> {
+        let size = block_size(len)?;
+        let first_class = size_class(size);
+        for class in first_class..NUM_SIZE_CLASSES {
+            let mut block = read_u64(&self.stable_memory, free_list_offset(class))?;
+            while block != NULL {
+                let free_size = self.size_of(block)?;
+                // Every block in a larger size class is large enough.
+                if free_size >= size {
+                    self.unlink(block, free_size)?;
+                    self.set_block(block, free_size, ALLOCATED)?;
+                    self.shrink(block, free_size, size)?;
+                    return Ok(block + 8);
+                }
+                block = read_u64(&self.stable_memory, block + 8)?;
+            }
+        }
+
+        let block = self.heap_end()?;
+        let heap_end = block.checked_add(size).ok_or(Error::OffsetOverflow)?;
+        // The footer is written first, as it is the write that grows the
+        // memory.
+        self.set_block(block, size, ALLOCATED)?;
+        write_u64(&self.stable_memory, HEAP_END_OFFSET, heap_end)?;
+        Ok(block + 8)
+    }
+
+    /// Frees the object at `address`, so that its space can be reused.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `address` was not returned by `alloc` or `realloc`, or has
+    /// already been freed.
+    pub fn free(&self, address: u64) -> Result<(), Error> {
+        let (block, size) = self.allocated_block(address)?;
+        self.release(block, size)
+    }
+
+    /// Resizes the object at `address` to `len` bytes and returns its new
+    /// address, which is the same as the old one if it could be resized in
+    /// place.
+    ///
+    /// The object is moved to a new block if it cannot be resized in place,
+    /// keeping as many of its bytes as fit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `address` was not returned by `alloc` or `realloc`, or has
+    /// already been freed.
+    pub fn realloc(&self, address: u64, len: u64) -> Result<u64, Error> {
+        let (block, mut current_size) = self.allocated_block(address)?;
+        let size = block_size(len)?;
+
+        if size > current_size {
+            let heap_end = self.heap_end()?;
+            let next = block + current_size;
+            if next == heap_end {
+                let new_heap_end = block.checked_add(size).ok_or(Error::OffsetOverflow)?;
+                self.set_block(block, size, ALLOCATED)?;
+                write_u64(&self.stable_memory, HEAP_END_OFFSET, new_heap_end)?;
+                return Ok(address);
+            }
+            let next_header = read_u64(&self.stable_memory, next)?;
+            let next_size = next_header & !ALLOCATED;
+            if next_header & ALLOCATED == 0 && current_size + next_size >= size {
+                self.unlink(next, next_size)?;
+                current_size += next_size;
+                self.set_block(block, current_size, ALLOCATED)?;
+            } else {
+                let new_address = self.alloc(len)?;
+                self.copy(address, new_address, current_size - BLOCK_OVERHEAD)?;
+                self.release(block, current_size)?;
+                return Ok(new_address);
+            }
+        }
+
+        self.shrink(block, current_size, size)?;
+        Ok(address)
+    }
+
+    /// Gets the number of bytes that can be stored at `address`, which is at
+    /// least the length it was allocated with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `address` was not returned by `alloc` or `realloc`, or has
+    /// already been freed.
+    pub fn capacity(&self, address: u64) -> Result<u64, Error> {
+        let (_, size) = self.allocated_block(address)?;
+        Ok(size - BLOCK_OVERHEAD)
+    }
+
+    fn size_of(&self, block: u64) -> Result<u64, Error> {
+        Ok(read_u64(&self.stable_memory, block)? & !ALLOCATED)
+    }
+
+    /// Writes the header and footer of a block.
+    fn set_block(&self, block: u64, size: u64, allocated: u64) -> Result<(), Error> {
+        write_u64(&self.stable_memory, block + size - 8, size | allocated)?;
+        write_u64(&self.stable_memory, block, size | allocated)
+    }
+
+    /// Finds the block of the object at `address`, checking that it is
+    /// allocated.
+    fn allocated_block(&self, address: u64) -> Result<(u64, u64), Error> {
+        let block = address.wrapping_sub(8);
+        let heap_end = self.heap_end()?;
+        assert!(
+            block >= HEAP_START && block < heap_end && (block - HEAP_START) & (BLOCK_ALIGNMENT - 1) == 0,
+            "Address was not allocated"
+        );
+        let header = read_u64(&self.stable_memory, block)?;
+        let size = header & !ALLOCATED;
+        assert!(
+            header & ALLOCATED != 0
+                && size >= MIN_BLOCK_SIZE
+                && block + size <= heap_end
+                && read_u64(&self.stable_memory, block + size - 8)? == header,
+            "Address was not allocated"
+        );
+        Ok((block, size))
+    }
+
+    /// Cuts an allocated block down to `size` bytes, releasing the rest if it
+    /// is large enough to make a block of its own.
+    fn shrink(&self, block: u64, current_size: u64, size: u64) -> Result<(), Error> {
+        if current_size - size >= MIN_BLOCK_SIZE {
+            self.set_block(block, size, ALLOCATED)?;
+            self.release(block + size, current_size - size)?;
+        }
+        Ok(())
+    }
+
+    /// Frees a block, merging it with the free blocks either side of it.
+    fn release(&self, mut block: u64, mut size: u64) -> Result<(), Error> {
+        if block > HEAP_START {
+            let previous_footer = read_u64(&self.stable_memory, block - 8)?;
+            if previous_footer & ALLOCATED == 0 {
+                let previous_size = previous_footer;
+                block -= previous_size;
+                size += previous_size;
+                self.unlink(block, previous_size)?;
+            }
+        }
+
+        let heap_end = self.heap_end()?;
+        let next = block + size;
+        if next == heap_end {
+            return write_u64(&self.stable_memory, HEAP_END_OFFSET, block);
+        }
+        let next_header = read_u64(&self.stable_memory, next)?;
+        if next_header & ALLOCATED == 0 {
+            self.unlink(next, next_header)?;
+            size += next_header;
+        }
+
+        self.set_block(block, size, 0)?;
+        self.push(block, size)
+    }
+
+    /// Adds a free block to the front of the list of its size class.
+    fn push(&self, block: u64, size: u64) -> Result<(), Error> {
+        let head_offset = free_list_offset(size_class(size));
+        let head = read_u64(&self.stable_memory, head_offset)?;
+        write_u64(&self.stable_memory, block + 8, head)?;
+        write_u64(&self.stable_memory, block + 16, NULL)?;
+        if head != NULL {
+            write_u64(&self.stable_memory, head + 16, block)?;
+        }
+        write_u64(&self.stable_memory, head_offset, block)
+    }
+
+    /// Takes a free block out of the list of its size class.
+    fn unlink(&self, block: u64, size: u64) -> Result<(), Error> {
+        let next = read_u64(&self.stable_memory, block + 8)?;
+        let previous = read_u64(&self.stable_memory, block + 16)?;
+        if previous == NULL {
+            write_u64(&self.stable_memory, free_list_offset(size_class(size)), next)?;
+        } else {
+            write_u64(&self.stable_memory, previous + 8, next)?;
+        }
+        if next != NULL {
+            write_u64(&self.stable_memory, next + 16, previous)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `len` bytes from one object to another, a chunk at a time.
+    fn copy(&self, from: u64, to: u64, len: u64) -> Result<(), Error> {
+        let mut buf = vec![0; COPY_CHUNK_SIZE_IN_BYTES.min(len) as usize];
+        let mut copied = 0;
+        while copied < len {
+            let chunk_len = (len - copied).min(COPY_CHUNK_SIZE_IN_BYTES) as usize;
+            let chunk = &mut buf[..chunk_len];
+            if read_at(&self.stable_memory, from + copied, chunk)? != chunk_len
+                || write_at(&self.stable_memory, to + copied, chunk)? != chunk_len
+            {
+                return Err(Error::OutOfBounds);
+            }
+            copied += chunk_len as u64;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use crate::memory::{Ic0StableMemory, Memory, WASM_PAGE_SIZE_IN_BYTES};
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// A write-back cache of fixed-size blocks in front of a memory.
+///
+/// Reads and writes are served from up to `max_blocks` cached blocks of
+/// `block_size` bytes each. A modified block is only written to the underlying
+/// memory when it is evicted, least recently used first, or when the cache is
+/// flushed. Clones share the same cache, and all access to the underlying
+/// memory must go through it.
+///
+/// Dirty blocks are written back when the last clone is dropped, but nothing is
+/// dropped on upgrade, so call `flush` from `pre_upgrade` to persist them.
+pub struct BlockCache<M: Memory = Ic0StableMemory> {
+    inner: Rc<RefCell<BlockCacheInner<M>>>,
+}
+
+struct Block {
+    bytes: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct BlockCacheInner<M: Memory> {
+    memory: M,
+    block_size: u64,
+    max_blocks: usize,
+    // Cached blocks by index, so that flushing writes them back in order.
+    blocks: BTreeMap<u64, Block>,
+    // The index of each cached block by when it was last used.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl<M: Memory> BlockCache<M> {
+    /// Creates a cache of up to `max_blocks` blocks of `block_size` bytes in
+    /// front of the given memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is not a power of two no larger than a
+    /// WebAssembly page, so that blocks never straddle the end of the memory,
+    /// or if `max_blocks` is 0.
+    pub fn new(memory: M, block_size: u64, max_blocks: usize) -> Self {
+        assert!(
+            block_size.is_power_of_two() && block_size <= WASM_PAGE_SIZE_IN_BYTES,
+            "Block size must be a power of two no larger than a page"
+        );
+        assert!(max_blocks != 0, "Cache must hold at least one block");
+        Self {
+            inner: Rc::new(RefCell::new(BlockCacheInner {
+                memory,
+                block_size,
+                max_blocks,
+                blocks: BTreeMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+            })),
+        }
+    }
+
+    /// Gets the size of each block in bytes.
+    pub fn block_size(&self) -> u64 {
+        self.inner.borrow().block_size
+    }
+
+    /// Gets the number of blocks the cache can hold.
+    pub fn max_blocks(&self) -> usize {
+        self.inner.borrow().max_blocks
+    }
+
+    /// Gets the number of cached blocks that have not been written back yet.
+    pub fn dirty_blocks(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.blocks.values().filter(|block| block.dirty).count()
+    }
+}
+
+impl<M: Memory> BlockCacheInner<M> {
+    fn check_bounds(&self, offset: u64, len: usize) {
+        let size_in_bytes = self.memory.size() * WASM_PAGE_SIZE_IN_BYTES;
+        assert!(
+            matches!(offset.checked_add(len as u64), Some(end) if end <= size_in_bytes),
+            "Cached memory access out of bounds"
+        );
+    }
+
+    /// Gets the cached block at `index`, first evicting the least recently
+    /// used block if the cache is full.
+    ///
+    /// Blocks that are about to be overwritten entirely need not be `load`ed
+    /// from the underlying memory.
+    fn block(&mut self, index: u64, load: bool) -> &mut Block {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(block) = self.blocks.get_mut(&index) {
+            self.recency.remove(&block.last_used);
+            self.recency.insert(clock, index);
+            block.last_used = clock;
+            return self.blocks.get_mut(&index).unwrap();
+        }
+
+        if self.blocks.len() >= self.max_blocks {
+            self.evict();
+        }
+        let mut bytes = vec![0; self.block_size as usize];
+        if load {
+            self.memory.read(index * self.block_size, &mut bytes);
+        }
+        self.recency.insert(clock, index);
+        self.blocks.entry(index).or_insert(Block {
+            bytes,
+            dirty: false,
+            last_used: clock,
+        })
+    }
+
+    fn evict(&mut self) {
+        let (&last_used, &index) = match self.recency.iter().next() {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.recency.remove(&last_used);
+        if let Some(block) = self.blocks.remove(&index) {
+            if block.dirty {
+                self.memory.write(index * self.block_size, &block.bytes);
+            }
+        }
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) {
+        self.check_bounds(offset, buf.len());
+        let block_size = self.block_size;
+        let mut start = 0;
+        while start < buf.len() {
+            let position = offset + start as u64;
+            let offset_in_block = (position % block_size) as usize;
+            let len = (buf.len() - start).min(block_size as usize - offset_in_block);
+            let block = self.block(position / block_size, true);
+            buf[start..start + len]
+                .copy_from_slice(&block.bytes[offset_in_block..offset_in_block + len]);
+            start += len;
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        self.check_bounds(offset, buf.len());
+        let block_size = self.block_size;
+        let mut start = 0;
+        while start < buf.len() {
+            let position = offset + start as u64;
+            let offset_in_block = (position % block_size) as usize;
+            let len = (buf.len() - start).min(block_size as usize - offset_in_block);
+            let whole_block = len as u64 == block_size;
+            let block = self.block(position / block_size, !whole_block);
+            block.bytes[offset_in_block..offset_in_block + len]
+                .copy_from_slice(&buf[start..start + len]);
+            block.dirty = true;
+            start += len;
+        }
+    }
+
+    fn flush(&mut self) {
+        let block_size = self.block_size;
+        for (index, block) in self.blocks.iter_mut().filter(|(_, block)| block.dirty) {
+            self.memory.write(index * block_size, &block.bytes);
+            block.dirty = false;
+        }
+        self.memory.flush();
+    }
+}
+
+impl<M: Memory> Drop for BlockCacheInner<M> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<M: Memory> Clone for BlockCache<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> Memory for BlockCache<M> {
+    fn size(&self) -> u64 {
+        self.inner.borrow().memory.size()
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        self.inner.borrow().memory.grow(added_pages)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being read is beyond the size of the memory.
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.inner.borrow_mut().read(offset, buf)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being written is beyond the size of the memory.
+    fn write(&self, offset: u64, buf: &[u8]) {
+        self.inner.borrow_mut().write(offset, buf)
+    }
+
+    /// Writes every dirty block back to the underlying memory, in order.
+    ///
+    /// The cached blocks are kept, so subsequent reads are still served from
+    /// the cache.
+    fn flush(&self) {
+        self.inner.borrow_mut().flush()
+    }
+}
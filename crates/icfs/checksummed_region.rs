@@ -0,0 +1,151 @@
+use crate::error::Error;
+use crate::internal::{
+    assert_sectors_fit, ensure_capacity, impl_sector_device_io, read_or_zero, sector_table_size, SectorDevice,
+};
+use crate::memory::{Ic0StableMemory, Memory};
+
+const CHECKSUM_SIZE_IN_BYTES: u64 = 4;
+
+/// A region of a memory split into fixed-size blocks, each with a CRC32C
+/// checksum that is verified whenever the block is read.
+///
+/// The checksums are kept in a table at `base`, followed by `num_blocks`
+/// blocks of `block_size` bytes, aligned to the block size. Offsets are
+/// relative to the first block. A block that has never been written reads as
+/// zeros, and the underlying memory is grown on demand when writing.
+///
+/// Reading a block whose contents do not match its checksum fails with
+/// `Error::Corrupted`, naming the block.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ChecksummedRegion<M: Memory = Ic0StableMemory> {
+    memory: M,
+    base: u64,
+    block_size: u64,
+    num_blocks: u64,
+    offset: u64,
+}
+
+fn block_offset<M: Memory>(region: &ChecksummedRegion<M>, index: u64) -> u64 {
+    // Checked in `new`.
+    let table_size = sector_table_size(region.block_size, region.num_blocks, CHECKSUM_SIZE_IN_BYTES).unwrap();
+    region.base + table_size + index * region.block_size
+}
+
+fn checksum_offset<M: Memory>(region: &ChecksummedRegion<M>, index: u64) -> u64 {
+    region.base + index * CHECKSUM_SIZE_IN_BYTES
+}
+
+/// Reads the block at `index` and verifies it against its checksum.
+///
+/// A block that has never been written has a checksum of 0 and is all zeros.
+fn read_block<M: Memory>(region: &ChecksummedRegion<M>, index: u64) -> Result<Vec<u8>, Error> {
+    let mut block = vec![0; region.block_size as usize];
+    read_or_zero(&region.memory, block_offset(region, index), &mut block);
+    let mut checksum = [0; CHECKSUM_SIZE_IN_BYTES as usize];
+    read_or_zero(&region.memory, checksum_offset(region, index), &mut checksum);
+    let checksum = u32::from_le_bytes(checksum);
+    let unwritten = checksum == 0 && block.iter().all(|byte| *byte == 0);
+    if !unwritten && checksum != crc32c::crc32c(&block) {
+        return Err(Error::Corrupted { block: index });
+    }
+    Ok(block)
+}
+
+fn write_block<M: Memory>(region: &ChecksummedRegion<M>, index: u64, block: &[u8]) -> Result<(), Error> {
+    let offset = block_offset(region, index);
+    // The checksum table comes first, so this covers the checksum too.
+    ensure_capacity(&region.memory, offset + region.block_size)?;
+    region.memory.write(offset, block);
+    let checksum = crc32c::crc32c(block);
+    region
+        .memory
+        .write(checksum_offset(region, index), &checksum.to_le_bytes());
+    Ok(())
+}
+
+impl<M: Memory> SectorDevice for ChecksummedRegion<M> {
+    fn sector_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        read_block(self, index)
+    }
+
+    fn write_sector(&self, index: u64, sector: &[u8]) -> Result<(), Error> {
+        write_block(self, index, sector)
+    }
+}
+
+impl_sector_device_io!(ChecksummedRegion);
+
+impl<M: Memory> ChecksummedRegion<M> {
+    /// Creates a region of `num_blocks` checksummed blocks of `block_size`
+    /// bytes, with its checksum table starting at `base` in the given memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is 0, or if the end of the region is not
+    /// addressable with 64 bits.
+    pub fn new(memory: M, base: u64, block_size: u64, num_blocks: u64) -> Self {
+        assert!(block_size != 0, "Block size must not be zero");
+        let table_size = sector_table_size(block_size, num_blocks, CHECKSUM_SIZE_IN_BYTES);
+        assert_sectors_fit(base, table_size, block_size, num_blocks);
+        Self {
+            memory,
+            base,
+            block_size,
+            num_blocks,
+            offset: 0,
+        }
+    }
+
+    /// Gets the offset of the region (i.e. of its checksum table) within the
+    /// underlying memory.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Gets the size of each block in bytes.
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Gets the number of blocks in the region.
+    pub fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    /// Gets the length of the data in the region in bytes.
+    pub fn len(&self) -> u64 {
+        SectorDevice::len(self)
+    }
+
+    /// Returns `true` if the region holds no data.
+    pub fn is_empty(&self) -> bool {
+        self.num_blocks == 0
+    }
+
+    /// Gets the offset just past the end of the region within the underlying
+    /// memory, where the next region could start.
+    pub fn end(&self) -> u64 {
+        block_offset(self, self.num_blocks)
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Verifies every block, returning the first corruption found.
+    ///
+    /// This reads the whole region, so it is best done when time allows (e.g.
+    /// in `post_upgrade`) rather than on every call.
+    pub fn verify(&self) -> Result<(), Error> {
+        (0..self.num_blocks).try_for_each(|index| read_block(self, index).map(|_| ()))
+    }
+}
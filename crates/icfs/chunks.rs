@@ -0,0 +1,63 @@
+use crate::internal::div_ceil;
+use crate::memory::Memory;
+use std::convert::TryFrom;
+
+/// An iterator over part of a memory in chunks of a fixed size.
+///
+/// The last chunk is shorter if the end is not a multiple of the chunk size.
+///
+/// Created by `StableMemory::chunks` and `StableMemory::chunks_to`.
+#[derive(Clone, Debug)]
+pub struct Chunks<'a, M: Memory> {
+    memory: &'a M,
+    base: u64,
+    offset: u64,
+    end: u64,
+    chunk_size: usize,
+}
+
+impl<'a, M: Memory> Chunks<'a, M> {
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub(crate) fn new(memory: &'a M, base: u64, end: u64, chunk_size: usize) -> Self {
+        assert!(chunk_size != 0, "Chunk size must not be zero");
+        Self {
+            memory,
+            base,
+            offset: 0,
+            end,
+            chunk_size,
+        }
+    }
+
+    /// Gets the offset of the next chunk, relative to where iteration started.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a, M: Memory> Iterator for Chunks<'a, M> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let len = (self.end - self.offset).min(self.chunk_size as u64) as usize;
+        let mut chunk = vec![0; len];
+        self.memory.read(self.base + self.offset, &mut chunk);
+        self.offset += len as u64;
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end.saturating_sub(self.offset);
+        let chunk_size = self.chunk_size as u64;
+        let chunks = div_ceil(remaining, chunk_size);
+        match usize::try_from(chunks) {
+            Ok(chunks) => (chunks, Some(chunks)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+// A region of a memory that stores fixed-size logical sectors compressed with
+// LZ4, behind a table mapping each sector to the extent that holds it.
+//
+// Each extent has a capacity of the compressed length rounded up to a power of
+// two, of at least 8 bytes and at most the sector size. A sector is rewritten in
+// place for as long as it needs the same capacity, and moved to an extent of
+// the capacity it needs otherwise. The extent it leaves is pushed onto a free
+// list for its capacity, linked through the first 8 bytes of each free extent,
+// and extents are taken from those lists before the end of the extents is
+// bumped. Links hold the offset of the next free extent plus 1, so that 0 ends
+// a list. A capacity that is not a power of two, i.e. the sector size, shares
+// the list of the power of two above it, which no other capacity uses. Sectors that do not compress are stored as they are, which is
+// signalled by a length equal to the sector size.
+//
+// Layout (from `base`):
+// -------------------------------------------------- <- Byte 0
+// End of the extents (in bytes)          ↕ 8 bytes
+// First free extent of capacity 1        ↕ 8 bytes
+// First free extent of capacity 2        ↕ 8 bytes
+// ...
+// First free extent of capacity 2^32     ↕ 8 bytes
+// Offset of the extent of sector 0       ↕ 8 bytes
+// Capacity of the extent of sector 0     ↕ 4 bytes
+// Length of sector 0 once compressed     ↕ 4 bytes
+// ...
+// Length of sector N - 1 once compressed ↕ 4 bytes
+// -------------------------------------------------- <- Byte 272 + N * 16
+// Extents                                ↕ Grows as needed
+use crate::error::Error;
+use crate::internal::{assert_sectors_fit, ensure_capacity, impl_sector_device_io, read_or_zero, SectorDevice};
+use crate::memory::{Ic0StableMemory, Memory};
+
+const EXTENTS_END_SIZE_IN_BYTES: u64 = 8;
+/// One free list per power of two up to 2^32, the power of two above the
+/// largest sector size.
+const NUM_FREE_LISTS: u64 = 33;
+const FREE_LIST_SIZE_IN_BYTES: u64 = 8;
+const HEADER_SIZE_IN_BYTES: u64 = EXTENTS_END_SIZE_IN_BYTES + NUM_FREE_LISTS * FREE_LIST_SIZE_IN_BYTES;
+const ENTRY_SIZE_IN_BYTES: u64 = 16;
+/// Every extent is large enough to link it into a free list.
+const MIN_CAPACITY_IN_BYTES: u64 = 8;
+
+/// A flat, fixed-size device over a memory that transparently compresses its
+/// contents in sectors.
+///
+/// Offsets are relative to the start of the first sector. A sector that has
+/// never been written reads as zeros, and the underlying memory is grown on
+/// demand when writing. Everything from `base` onwards is used by the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CompressedMemory<M: Memory = Ic0StableMemory> {
+    memory: M,
+    base: u64,
+    sector_size: u64,
+    num_sectors: u64,
+    offset: u64,
+}
+
+/// How much space the data in a `CompressedMemory` takes up.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressionStats {
+    /// The size of the sectors that have been written.
+    pub logical_bytes: u64,
+    /// The size of those sectors once compressed.
+    pub compressed_bytes: u64,
+    /// The number of bytes of the underlying memory in use, including the
+    /// table of extents and any space left over in them.
+    pub physical_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Gets the ratio of the size of the sectors written to their compressed
+    /// size, e.g. 2.0 when they compress to half their size.
+    ///
+    /// This is 1.0 if nothing has been written.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.logical_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
+}
+
+/// Where a sector is stored.
+#[derive(Copy, Clone, Default)]
+struct Extent {
+    offset: u64,
+    capacity: u32,
+    len: u32,
+}
+
+fn table_size(num_sectors: u64) -> Option<u64> {
+    num_sectors
+        .checked_mul(ENTRY_SIZE_IN_BYTES)?
+        .checked_add(HEADER_SIZE_IN_BYTES)
+}
+
+fn extents_offset<M: Memory>(compressed_memory: &CompressedMemory<M>) -> u64 {
+    // Checked in `new`.
+    compressed_memory.base + table_size(compressed_memory.num_sectors).unwrap()
+}
+
+fn entry_offset<M: Memory>(compressed_memory: &CompressedMemory<M>, index: u64) -> u64 {
+    compressed_memory.base + HEADER_SIZE_IN_BYTES + index * ENTRY_SIZE_IN_BYTES
+}
+
+/// Gets the offset of the head of the free list for extents of `capacity`
+/// bytes.
+fn free_list_offset<M: Memory>(compressed_memory: &CompressedMemory<M>, capacity: u64) -> u64 {
+    let index = capacity.next_power_of_two().trailing_zeros() as u64;
+    compressed_memory.base + EXTENTS_END_SIZE_IN_BYTES + index * FREE_LIST_SIZE_IN_BYTES
+}
+
+fn read_u64<M: Memory>(compressed_memory: &CompressedMemory<M>, offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    read_or_zero(&compressed_memory.memory, offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn get_extents_end<M: Memory>(compressed_memory: &CompressedMemory<M>) -> u64 {
+    read_u64(compressed_memory, compressed_memory.base)
+}
+
+fn set_extents_end<M: Memory>(compressed_memory: &CompressedMemory<M>, end: u64) {
+    compressed_memory
+        .memory
+        .write(compressed_memory.base, &end.to_le_bytes())
+}
+
+fn get_extent<M: Memory>(compressed_memory: &CompressedMemory<M>, index: u64) -> Extent {
+    let mut entry = [0; ENTRY_SIZE_IN_BYTES as usize];
+    read_or_zero(&compressed_memory.memory, entry_offset(compressed_memory, index), &mut entry);
+    let mut offset = [0; 8];
+    offset.copy_from_slice(&entry[0..8]);
+    let mut capacity = [0; 4];
+    capacity.copy_from_slice(&entry[8..12]);
+    let mut len = [0; 4];
+    len.copy_from_slice(&entry[12..16]);
+    Extent {
+        offset: u64::from_le_bytes(offset),
+        capacity: u32::from_le_bytes(capacity),
+        len: u32::from_le_bytes(len),
+    }
+}
+
+fn set_extent<M: Memory>(compressed_memory: &CompressedMemory<M>, index: u64, extent: Extent) {
+    let mut entry = [0; ENTRY_SIZE_IN_BYTES as usize];
+    entry[0..8].copy_from_slice(&extent.offset.to_le_bytes());
+    entry[8..12].copy_from_slice(&extent.capacity.to_le_bytes());
+    entry[12..16].copy_from_slice(&extent.len.to_le_bytes());
+    compressed_memory
+        .memory
+        .write(entry_offset(compressed_memory, index), &entry)
+}
+
+/// Reads and decompresses the sector at `index`.
+fn decompress_sector<M: Memory>(compressed_memory: &CompressedMemory<M>, index: u64) -> Result<Vec<u8>, Error> {
+    let sector_size = compressed_memory.sector_size as usize;
+    let extent = get_extent(compressed_memory, index);
+    if extent.len == 0 {
+        return Ok(vec![0; sector_size]);
+    }
+    let mut stored = vec![0; extent.len as usize];
+    read_or_zero(
+        &compressed_memory.memory,
+        extents_offset(compressed_memory) + extent.offset,
+        &mut stored,
+    );
+    if stored.len() == sector_size {
+        return Ok(stored);
+    }
+    match lz4_flex::block::decompress(&stored, sector_size) {
+        Ok(sector) if sector.len() == sector_size => Ok(sector),
+        _ => Err(Error::DecompressionFailed { sector: index }),
+    }
+}
+
+/// Takes an extent of `capacity` bytes from its free list, or from the end of
+/// the extents if the list is empty, returning its offset.
+fn allocate_extent<M: Memory>(compressed_memory: &CompressedMemory<M>, capacity: u64) -> Result<u64, Error> {
+    let free_list = free_list_offset(compressed_memory, capacity);
+    let head = read_u64(compressed_memory, free_list);
+    if head != 0 {
+        let offset = head - 1;
+        let next = read_u64(compressed_memory, extents_offset(compressed_memory) + offset);
+        compressed_memory.memory.write(free_list, &next.to_le_bytes());
+        return Ok(offset);
+    }
+    let offset = get_extents_end(compressed_memory);
+    let end = offset.checked_add(capacity).ok_or(Error::OffsetOverflow)?;
+    let memory_end = extents_offset(compressed_memory)
+        .checked_add(end)
+        .ok_or(Error::OffsetOverflow)?;
+    ensure_capacity(&compressed_memory.memory, memory_end)?;
+    set_extents_end(compressed_memory, end);
+    Ok(offset)
+}
+
+/// Pushes an extent that is no longer used onto the free list for its
+/// capacity.
+fn free_extent<M: Memory>(compressed_memory: &CompressedMemory<M>, extent: Extent) {
+    let free_list = free_list_offset(compressed_memory, extent.capacity as u64);
+    let head = read_u64(compressed_memory, free_list);
+    compressed_memory.memory.write(
+        extents_offset(compressed_memory) + extent.offset,
+        &head.to_le_bytes(),
+    );
+    compressed_memory
+        .memory
+        .write(free_list, &(extent.offset + 1).to_le_bytes());
+}
+
+/// Compresses and writes the sector at `index`, moving it to another extent if
+/// it needs a different capacity than its old one.
+fn compress_sector<M: Memory>(compressed_memory: &CompressedMemory<M>, index: u64, sector: &[u8]) -> Result<(), Error> {
+    let compressed = lz4_flex::block::compress(sector);
+    let stored = if compressed.len() < sector.len() {
+        &compressed[..]
+    } else {
+        sector
+    };
+
+    // Never more than the sector size, which is checked to fit in a `u32` in
+    // `new`, unless that is less than the minimum.
+    let capacity = (stored.len() as u64)
+        .next_power_of_two()
+        .min(compressed_memory.sector_size)
+        .max(MIN_CAPACITY_IN_BYTES);
+    let old_extent = get_extent(compressed_memory, index);
+    let mut extent = old_extent;
+    if capacity != extent.capacity as u64 {
+        extent.offset = allocate_extent(compressed_memory, capacity)?;
+        extent.capacity = capacity as u32;
+    }
+    compressed_memory.memory.write(
+        extents_offset(compressed_memory) + extent.offset,
+        stored,
+    );
+    extent.len = stored.len() as u32;
+    set_extent(compressed_memory, index, extent);
+    // A sector that has never been written has no extent to free.
+    if extent.offset != old_extent.offset && old_extent.capacity != 0 {
+        free_extent(compressed_memory, old_extent);
+    }
+    Ok(())
+}
+
+impl<M: Memory> SectorDevice for CompressedMemory<M> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        decompress_sector(self, index)
+    }
+
+    fn write_sector(&self, index: u64, sector: &[u8]) -> Result<(), Error> {
+        compress_sector(self, index, sector)
+    }
+}
+
+impl_sector_device_io!(CompressedMemory);
+
+impl<M: Memory> CompressedMemory<M> {
+    /// Creates a device of `num_sectors` sectors of `sector_size` bytes, with
+    /// its table of extents starting at `base` in the given memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` is 0 or does not fit in a `u32`, or if the end
+    /// of the table of extents followed by the sectors, uncompressed, is not
+    /// addressable with 64 bits.
+    pub fn new(memory: M, base: u64, sector_size: u64, num_sectors: u64) -> Self {
+        assert!(
+            sector_size != 0 && sector_size <= u32::MAX as u64,
+            "Sector size must be between 1 and u32::MAX"
+        );
+        assert_sectors_fit(base, table_size(num_sectors), sector_size, num_sectors);
+        Self {
+            memory,
+            base,
+            sector_size,
+            num_sectors,
+            offset: 0,
+        }
+    }
+
+    /// Gets the offset of the device (i.e. of its table of extents) within
+    /// the underlying memory.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Gets the size of each sector in bytes.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Gets the number of sectors in the device.
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Gets the length of the device in bytes, before compression.
+    pub fn len(&self) -> u64 {
+        SectorDevice::len(self)
+    }
+
+    /// Returns `true` if the device holds no data.
+    pub fn is_empty(&self) -> bool {
+        self.num_sectors == 0
+    }
+
+    /// Gets the number of bytes of the underlying memory in use, from `base`.
+    pub fn physical_bytes(&self) -> u64 {
+        // Checked in `new`.
+        table_size(self.num_sectors).unwrap() + get_extents_end(self)
+    }
+
+    /// Gets how much space the data takes up, before and after compression.
+    ///
+    /// This reads the whole table of extents.
+    pub fn stats(&self) -> CompressionStats {
+        let (logical_bytes, compressed_bytes) = (0..self.num_sectors)
+            .map(|index| get_extent(self, index))
+            .filter(|extent| extent.len != 0)
+            .fold((0, 0), |(logical_bytes, compressed_bytes), extent| {
+                (logical_bytes + self.sector_size, compressed_bytes + extent.len as u64)
+            });
+        CompressionStats {
+            logical_bytes,
+            compressed_bytes,
+            physical_bytes: self.physical_bytes(),
+        }
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
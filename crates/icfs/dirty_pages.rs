@@ -0,0 +1,155 @@
+use crate::error::Error;
+use crate::internal::ensure_capacity;
+use crate::memory::{Memory, WASM_PAGE_SIZE_IN_BYTES};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Default)]
+struct Tracker {
+    pages: BTreeSet<u64>,
+    needs_full_resync: bool,
+}
+
+thread_local! {
+    // The trackers are kept here rather than in the handles, so that the
+    // handles, and the `StableMemory` handles they are attached to, stay
+    // `Copy`.
+    static TRACKERS: RefCell<Vec<Tracker>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Tracks which pages of a memory have been written since the last
+/// checkpoint, so that they can be shipped elsewhere as a delta.
+///
+/// Attach it to the handles that write to the memory with
+/// `StableMemory::with_dirty_pages`. Copies share the same set of pages, which
+/// lives for as long as the canister does.
+///
+/// The pages are tracked on the heap, so they are lost on upgrade. A tracker
+/// created for a memory that already holds data cannot tell which pages were
+/// written since the last delta was shipped, so it starts out needing a full
+/// resync, which `export_delta` reports.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DirtyPages {
+    id: usize,
+}
+
+impl DirtyPages {
+    /// Creates a tracker for the writes made to `memory` from now on.
+    pub fn new<M: Memory>(memory: &M) -> Self {
+        let tracker = Tracker {
+            pages: BTreeSet::new(),
+            needs_full_resync: memory.size() != 0,
+        };
+        TRACKERS.with(|trackers| {
+            let mut trackers = trackers.borrow_mut();
+            trackers.push(tracker);
+            Self {
+                id: trackers.len() - 1,
+            }
+        })
+    }
+
+    fn with_tracker<T>(&self, f: impl FnOnce(&mut Tracker) -> T) -> T {
+        TRACKERS.with(|trackers| f(&mut trackers.borrow_mut()[self.id]))
+    }
+
+    /// Marks the pages spanned by `len` bytes at `offset` in the memory as
+    /// dirty.
+    pub(crate) fn mark(&self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first = offset / WASM_PAGE_SIZE_IN_BYTES;
+        let last = (offset + (len - 1)) / WASM_PAGE_SIZE_IN_BYTES;
+        self.with_tracker(|tracker| tracker.pages.extend(first..=last));
+    }
+
+    /// Marks a page as dirty, e.g. to include it again in the next delta after
+    /// the one it was in could not be delivered.
+    pub fn mark_page(&self, page: u64) {
+        self.with_tracker(|tracker| tracker.pages.insert(page));
+    }
+
+    /// Gets the indices of the pages written since the last checkpoint, in
+    /// ascending order.
+    pub fn pages(&self) -> Vec<u64> {
+        self.with_tracker(|tracker| tracker.pages.iter().copied().collect())
+    }
+
+    /// Gets the number of pages written since the last checkpoint.
+    pub fn len(&self) -> usize {
+        self.with_tracker(|tracker| tracker.pages.len())
+    }
+
+    /// Returns `true` if no pages have been written since the last checkpoint.
+    pub fn is_empty(&self) -> bool {
+        self.with_tracker(|tracker| tracker.pages.is_empty())
+    }
+
+    /// Returns `true` if the pages written since the last delta was shipped
+    /// are not known, e.g. after an upgrade, so that the whole memory must be
+    /// shipped instead, such as with an `ImageExport`.
+    pub fn needs_full_resync(&self) -> bool {
+        self.with_tracker(|tracker| tracker.needs_full_resync)
+    }
+
+    /// Starts a new checkpoint, returning the indices of the pages written
+    /// since the last one, in ascending order.
+    ///
+    /// This also clears the need for a full resync, so only call it once the
+    /// whole memory has been shipped if one was needed.
+    pub fn checkpoint(&self) -> Vec<u64> {
+        self.with_tracker(|tracker| {
+            tracker.needs_full_resync = false;
+            std::mem::take(&mut tracker.pages).into_iter().collect()
+        })
+    }
+
+    /// Starts a new checkpoint, returning the index and contents of each page
+    /// written since the last one, in ascending order.
+    ///
+    /// Returns `None` instead if a full resync is needed, in which case the
+    /// whole memory must be shipped before any later delta can be applied.
+    /// Pages written from now on are still tracked for the next delta.
+    ///
+    /// `memory` must be the memory whose writes are being tracked.
+    pub fn export_delta<M: Memory>(&self, memory: &M) -> Option<Vec<(u64, Vec<u8>)>> {
+        let needs_full_resync = self.needs_full_resync();
+        let pages = self.checkpoint();
+        if needs_full_resync {
+            return None;
+        }
+        let delta = pages
+            .into_iter()
+            .map(|page| {
+                let mut bytes = vec![0; WASM_PAGE_SIZE_IN_BYTES as usize];
+                memory.read(page * WASM_PAGE_SIZE_IN_BYTES, &mut bytes);
+                (page, bytes)
+            })
+            .collect();
+        Some(delta)
+    }
+
+    /// Writes the pages of a delta exported with `export_delta` to a memory,
+    /// growing it as needed.
+    ///
+    /// Applying every delta of a memory in order, starting from an empty
+    /// memory or from a full copy taken when a full resync was needed,
+    /// reproduces its contents.
+    pub fn apply_delta<M: Memory>(memory: &M, delta: &[(u64, Vec<u8>)]) -> Result<(), Error> {
+        for (page, bytes) in delta {
+            if bytes.len() as u64 > WASM_PAGE_SIZE_IN_BYTES {
+                return Err(Error::OutOfBounds);
+            }
+            let offset = page
+                .checked_mul(WASM_PAGE_SIZE_IN_BYTES)
+                .ok_or(Error::OffsetOverflow)?;
+            let end = offset
+                .checked_add(bytes.len() as u64)
+                .ok_or(Error::OffsetOverflow)?;
+            ensure_capacity(memory, end)?;
+            memory.write(offset, bytes);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use crate::error::Error;
+use crate::internal::{
+    assert_sectors_fit, ensure_capacity, impl_sector_device_io, read_or_zero, sector_table_size, SectorDevice,
+};
+use crate::memory::{Ic0StableMemory, Memory};
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+
+// Each sector has a write counter and an authentication tag, kept out of band.
+// They follow the high-water mark, which has a tag of its own.
+const COUNTER_SIZE_IN_BYTES: u64 = 8;
+const TAG_SIZE_IN_BYTES: u64 = 16;
+const METADATA_SIZE_IN_BYTES: u64 = COUNTER_SIZE_IN_BYTES + TAG_SIZE_IN_BYTES;
+
+/// The sector number in the nonce of the high-water mark, which no sector can
+/// have.
+const MARK_NONCE_INDEX: u64 = u64::MAX;
+
+/// A region of a memory split into fixed-size sectors, each encrypted with
+/// XChaCha20-Poly1305 under a key supplied when it is created.
+///
+/// Each sector is sealed under a nonce made of the region id, its sector number
+/// and a counter of how many times it has been written. Nonces are therefore
+/// only unique for a key as long as every region encrypted under it has its own
+/// region id, and as long as the counters are never rolled back: restoring an
+/// older copy of the region, or formatting it again, under the same key and
+/// region id reuses nonces and gives the key away. Use a fresh key or region id
+/// in those cases.
+///
+/// The counters and authentication tags are kept out of band, in a table at
+/// `base`, followed by `num_sectors` sectors of `sector_size` bytes, aligned to
+/// the sector size. Offsets are relative to the first sector. A sector that has
+/// never been written reads as zeros, and the underlying memory is grown on
+/// demand when writing.
+///
+/// The table also holds an authenticated high-water mark, below which every
+/// sector has been written, so that a written sector cannot be passed off as
+/// never written. Writing a sector past the mark first writes zeros to the
+/// sectors skipped over, so regions are best filled from the start.
+///
+/// Reading a sector that has been tampered with, or with the wrong key, fails
+/// with `Error::DecryptionFailed`, naming the sector.
+#[derive(Clone)]
+pub struct EncryptedMemory<M: Memory = Ic0StableMemory> {
+    memory: M,
+    cipher: XChaCha20Poly1305,
+    region_id: u64,
+    base: u64,
+    sector_size: u64,
+    num_sectors: u64,
+    offset: u64,
+}
+
+/// Gets the size of the table in bytes, with the high-water mark first and the
+/// metadata of each sector after it.
+fn table_size(sector_size: u64, num_sectors: u64) -> Option<u64> {
+    sector_table_size(sector_size, num_sectors.checked_add(1)?, METADATA_SIZE_IN_BYTES)
+}
+
+fn sector_offset<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64) -> u64 {
+    // Checked in `new`.
+    let table_size = table_size(encrypted_memory.sector_size, encrypted_memory.num_sectors).unwrap();
+    encrypted_memory.base + table_size + index * encrypted_memory.sector_size
+}
+
+/// Gets the offset of an entry of the table, where entry 0 is the high-water
+/// mark and entry `index + 1` is the metadata of the sector at `index`.
+fn entry_offset<M: Memory>(encrypted_memory: &EncryptedMemory<M>, entry: u64) -> u64 {
+    encrypted_memory.base + entry * METADATA_SIZE_IN_BYTES
+}
+
+fn nonce<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64, counter: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[0..8].copy_from_slice(&index.to_le_bytes());
+    nonce[8..16].copy_from_slice(&counter.to_le_bytes());
+    nonce[16..24].copy_from_slice(&encrypted_memory.region_id.to_le_bytes());
+    nonce
+}
+
+fn read_entry<M: Memory>(encrypted_memory: &EncryptedMemory<M>, entry: u64) -> (u64, Tag) {
+    let mut bytes = [0; METADATA_SIZE_IN_BYTES as usize];
+    read_or_zero(&encrypted_memory.memory, entry_offset(encrypted_memory, entry), &mut bytes);
+    let mut counter = [0; COUNTER_SIZE_IN_BYTES as usize];
+    counter.copy_from_slice(&bytes[..COUNTER_SIZE_IN_BYTES as usize]);
+    let tag = Tag::clone_from_slice(&bytes[COUNTER_SIZE_IN_BYTES as usize..]);
+    (u64::from_le_bytes(counter), tag)
+}
+
+fn write_entry<M: Memory>(encrypted_memory: &EncryptedMemory<M>, entry: u64, counter: u64, tag: &Tag) {
+    let mut bytes = [0; METADATA_SIZE_IN_BYTES as usize];
+    bytes[..COUNTER_SIZE_IN_BYTES as usize].copy_from_slice(&counter.to_le_bytes());
+    bytes[COUNTER_SIZE_IN_BYTES as usize..].copy_from_slice(tag);
+    encrypted_memory
+        .memory
+        .write(entry_offset(encrypted_memory, entry), &bytes);
+}
+
+/// Reads the write counter and authentication tag of the sector at `index`.
+///
+/// A sector that has never been written has a counter of 0.
+fn read_metadata<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64) -> (u64, Tag) {
+    read_entry(encrypted_memory, index + 1)
+}
+
+/// Reads and verifies the high-water mark, on behalf of the sector at `index`.
+///
+/// Every sector below the mark has been written. A region that has never been
+/// written has a mark of 0.
+fn read_mark<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64) -> Result<u64, Error> {
+    let (mark, tag) = read_entry(encrypted_memory, 0);
+    if mark == 0 {
+        return Ok(mark);
+    }
+    encrypted_memory
+        .cipher
+        .decrypt_in_place_detached(&nonce(encrypted_memory, MARK_NONCE_INDEX, mark), &[], &mut [], &tag)
+        .map_err(|_| Error::DecryptionFailed { sector: index })?;
+    Ok(mark)
+}
+
+/// Writes the high-water mark, which only ever goes up, so that its nonce is
+/// never reused.
+fn write_mark<M: Memory>(encrypted_memory: &EncryptedMemory<M>, mark: u64) {
+    let tag = encrypted_memory
+        .cipher
+        .encrypt_in_place_detached(&nonce(encrypted_memory, MARK_NONCE_INDEX, mark), &[], &mut [])
+        .expect("Unable to seal the high-water mark");
+    write_entry(encrypted_memory, 0, mark, &tag);
+}
+
+/// Reads and decrypts the sector at `index`.
+///
+/// A sector that has never been written reads as zeros, while one below the
+/// high-water mark whose counter has been zeroed fails to decrypt.
+fn decrypt_sector<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64) -> Result<Vec<u8>, Error> {
+    let (counter, tag) = read_metadata(encrypted_memory, index);
+    let mut sector = vec![0; encrypted_memory.sector_size as usize];
+    if counter == 0 {
+        if index < read_mark(encrypted_memory, index)? {
+            return Err(Error::DecryptionFailed { sector: index });
+        }
+        return Ok(sector);
+    }
+    read_or_zero(&encrypted_memory.memory, sector_offset(encrypted_memory, index), &mut sector);
+    encrypted_memory
+        .cipher
+        .decrypt_in_place_detached(&nonce(encrypted_memory, index, counter), &[], &mut sector, &tag)
+        .map_err(|_| Error::DecryptionFailed { sector: index })?;
+    Ok(sector)
+}
+
+/// Encrypts and writes the sector at `index`, raising the high-water mark past
+/// it if needed.
+fn encrypt_sector<M: Memory>(encrypted_memory: &EncryptedMemory<M>, index: u64, sector: &[u8]) -> Result<(), Error> {
+    let mark = read_mark(encrypted_memory, index)?;
+    // The sectors are written before the mark is raised, so that an
+    // interrupted write never leaves an unwritten sector below it. Sectors
+    // past the mark that were written before such an interruption are kept.
+    for skipped in mark..index {
+        let (counter, _) = read_metadata(encrypted_memory, skipped);
+        if counter == 0 {
+            let mut zeros = vec![0; encrypted_memory.sector_size as usize];
+            seal_sector(encrypted_memory, skipped, counter, &mut zeros)?;
+        }
+    }
+    let (counter, _) = read_metadata(encrypted_memory, index);
+    seal_sector(encrypted_memory, index, counter, &mut sector.to_vec())?;
+    if index >= mark {
+        write_mark(encrypted_memory, index + 1);
+    }
+    Ok(())
+}
+
+/// Encrypts and writes the sector at `index`, which had been written `counter`
+/// times before.
+fn seal_sector<M: Memory>(
+    encrypted_memory: &EncryptedMemory<M>,
+    index: u64,
+    counter: u64,
+    sector: &mut [u8],
+) -> Result<(), Error> {
+    let offset = sector_offset(encrypted_memory, index);
+    // The metadata table comes first, so this covers the metadata too.
+    ensure_capacity(&encrypted_memory.memory, offset + encrypted_memory.sector_size)?;
+    // Reusing a nonce would give the key away, so never wrap around.
+    let counter = counter.checked_add(1).expect("Sector write counter overflowed");
+    let tag = encrypted_memory
+        .cipher
+        .encrypt_in_place_detached(&nonce(encrypted_memory, index, counter), &[], sector)
+        .expect("Sector too large to encrypt");
+    encrypted_memory.memory.write(offset, sector);
+    write_entry(encrypted_memory, index + 1, counter, &tag);
+    Ok(())
+}
+
+impl<M: Memory> SectorDevice for EncryptedMemory<M> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        decrypt_sector(self, index)
+    }
+
+    fn write_sector(&self, index: u64, sector: &[u8]) -> Result<(), Error> {
+        encrypt_sector(self, index, sector)
+    }
+}
+
+impl_sector_device_io!(EncryptedMemory);
+
+impl<M: Memory> EncryptedMemory<M> {
+    /// Creates an encrypted region of `num_sectors` sectors of `sector_size`
+    /// bytes, with its metadata table starting at `base` in the given memory.
+    ///
+    /// The same `key` and `region_id` must be supplied every time the region
+    /// is opened, e.g. in `init` and `post_upgrade`. Regions that share a key
+    /// must each have a different `region_id`, even if they live in different
+    /// memories.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` is 0, or if the end of the region is not
+    /// addressable with 64 bits.
+    pub fn new(
+        memory: M,
+        base: u64,
+        sector_size: u64,
+        num_sectors: u64,
+        key: &[u8; 32],
+        region_id: u64,
+    ) -> Self {
+        assert!(sector_size != 0, "Sector size must not be zero");
+        assert_sectors_fit(base, table_size(sector_size, num_sectors), sector_size, num_sectors);
+        Self {
+            memory,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            region_id,
+            base,
+            sector_size,
+            num_sectors,
+            offset: 0,
+        }
+    }
+
+    /// Gets the id that sets the nonces of this region apart from those of
+    /// other regions encrypted under the same key.
+    pub fn region_id(&self) -> u64 {
+        self.region_id
+    }
+
+    /// Gets the offset of the region (i.e. of its metadata table) within the
+    /// underlying memory.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Gets the size of each sector in bytes.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Gets the number of sectors in the region.
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Gets the length of the plaintext in the region in bytes.
+    pub fn len(&self) -> u64 {
+        SectorDevice::len(self)
+    }
+
+    /// Returns `true` if the region holds no data.
+    pub fn is_empty(&self) -> bool {
+        self.num_sectors == 0
+    }
+
+    /// Gets the offset just past the end of the region within the underlying
+    /// memory, where the next region could start.
+    pub fn end(&self) -> u64 {
+        sector_offset(self, self.num_sectors)
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io;
+
+/// The ways in which accessing a memory through icfs can fail.
+///
+/// Converts into an `io::Error` of the matching kind, from which it can be
+/// recovered with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The offset being read from or written to is past the end of the memory.
+    OutOfBounds,
+    /// The memory could not be grown by the `requested` number of pages.
+    ///
+    /// `available` is the size of the memory in pages at the time.
+    GrowFailed { requested: u64, available: u64 },
+    /// A seek would have moved before byte 0.
+    SeekBeforeStart,
+    /// An offset calculation overflowed.
+    OffsetOverflow,
+    /// A transaction was begun while another one was still in progress.
+    TransactionInProgress,
+    /// A transaction was written to or ended without having been begun.
+    NoTransaction,
+    /// The checksum of the given block does not match its contents.
+    Corrupted { block: u64 },
+    /// The given sector could not be decrypted, because it has been tampered
+    /// with or the key is wrong.
+    DecryptionFailed { sector: u64 },
+    /// The given sector could not be decompressed, because its compressed
+    /// bytes have been overwritten.
+    DecompressionFailed { sector: u64 },
+    /// A chunk of an image was imported out of order, or does not belong to
+    /// the same image as the chunks before it.
+    ///
+    /// `expected_offset` is the offset of the chunk that should come next.
+    UnexpectedChunk { expected_offset: u64 },
+    /// The hash of the image up to the chunk at the given offset does not
+    /// match the hash sent with it.
+    HashMismatch { offset: u64 },
+    /// No value has been saved at the given offset, or saving it did not
+    /// finish.
+    NoSavedValue { offset: u64 },
+}
+
+impl Error {
+    /// Gets the `io::ErrorKind` that this error converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::OutOfBounds => io::ErrorKind::UnexpectedEof,
+            Error::GrowFailed { .. } => io::ErrorKind::StorageFull,
+            Error::SeekBeforeStart => io::ErrorKind::InvalidInput,
+            Error::OffsetOverflow => io::ErrorKind::InvalidInput,
+            Error::TransactionInProgress => io::ErrorKind::ResourceBusy,
+            Error::NoTransaction => io::ErrorKind::InvalidInput,
+            Error::Corrupted { .. } => io::ErrorKind::InvalidData,
+            Error::DecryptionFailed { .. } => io::ErrorKind::InvalidData,
+            Error::DecompressionFailed { .. } => io::ErrorKind::InvalidData,
+            Error::UnexpectedChunk { .. } => io::ErrorKind::InvalidInput,
+            Error::HashMismatch { .. } => io::ErrorKind::InvalidData,
+            Error::NoSavedValue { .. } => io::ErrorKind::NotFound,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OutOfBounds => write!(f, "Attempt to access memory out of bounds"),
+            Error::GrowFailed {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Unable to grow memory of {} pages by {} pages",
+                available, requested
+            ),
+            Error::SeekBeforeStart => write!(f, "Attempt to seek before byte 0"),
+            Error::OffsetOverflow => write!(f, "Offset overflowed"),
+            Error::TransactionInProgress => write!(f, "A transaction is already in progress"),
+            Error::NoTransaction => write!(f, "No transaction is in progress"),
+            Error::Corrupted { block } => write!(f, "Checksum mismatch in block {}", block),
+            Error::DecryptionFailed { sector } => write!(f, "Unable to decrypt sector {}", sector),
+            Error::DecompressionFailed { sector } => {
+                write!(f, "Unable to decompress sector {}", sector)
+            }
+            Error::UnexpectedChunk { expected_offset } => {
+                write!(f, "Expected the chunk at offset {}", expected_offset)
+            }
+            Error::HashMismatch { offset } => {
+                write!(f, "SHA-256 mismatch in the chunk at offset {}", offset)
+            }
+            Error::NoSavedValue { offset } => write!(f, "No value has been saved at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
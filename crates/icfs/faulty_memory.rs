@@ -0,0 +1,135 @@
+use crate::memory::Memory;
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+#[derive(Clone, Debug, Default)]
+struct Faults {
+    /// The number of pages that can still be added before growing fails.
+    remaining_pages: Option<u64>,
+    /// The offset from which written bytes are dropped.
+    cut_offset: Option<u64>,
+    /// The size of the sectors, if the next write is to be torn.
+    tear_sector_size: Option<u64>,
+    /// The bits to flip in each byte read, by offset.
+    flips: BTreeMap<u64, u8>,
+}
+
+/// A memory that misbehaves on demand, for testing how the layers above it
+/// cope with a faulty device.
+///
+/// It behaves like the memory it wraps until told otherwise. Clones share the
+/// same faults, so a clone can be kept to inject faults into a memory that has
+/// been handed to another layer.
+#[derive(Clone, Debug, Default)]
+pub struct FaultyMemory<M: Memory> {
+    memory: M,
+    faults: Rc<RefCell<Faults>>,
+}
+
+impl<M: Memory> FaultyMemory<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            faults: Rc::new(RefCell::new(Faults::default())),
+        }
+    }
+
+    /// Makes growing fail once `pages` more pages have been added.
+    ///
+    /// A grow that would add more pages than are left fails without adding
+    /// any.
+    pub fn fail_grows_after(&self, pages: u64) {
+        self.faults.borrow_mut().remaining_pages = Some(pages)
+    }
+
+    /// Silently drops the bytes of every write from `offset` onwards, as if
+    /// the device had lost them.
+    pub fn cut_writes_at(&self, offset: u64) {
+        self.faults.borrow_mut().cut_offset = Some(offset)
+    }
+
+    /// Tears the next write that reaches halfway through a sector of
+    /// `sector_size` bytes, as if the device had lost power there.
+    ///
+    /// The bytes of the write up to the first halfway point are written and
+    /// the rest are dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sector_size` is less than 2.
+    pub fn tear_next_write(&self, sector_size: u64) {
+        assert!(sector_size >= 2, "Sectors must be at least 2 bytes long");
+        self.faults.borrow_mut().tear_sector_size = Some(sector_size)
+    }
+
+    /// Flips the bits set in `mask` in the byte at `offset` whenever it is
+    /// read, leaving the memory itself as it is.
+    pub fn flip_bits_on_read(&self, offset: u64, mask: u8) {
+        *self.faults.borrow_mut().flips.entry(offset).or_insert(0) ^= mask
+    }
+
+    /// Removes every fault, so that the memory behaves again.
+    pub fn heal(&self) {
+        *self.faults.borrow_mut() = Faults::default()
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M: Memory> Memory for FaultyMemory<M> {
+    fn size(&self) -> u64 {
+        self.memory.size()
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        let mut faults = self.faults.borrow_mut();
+        if let Some(remaining_pages) = faults.remaining_pages {
+            if added_pages > remaining_pages {
+                return Err(StableMemoryError::OutOfMemory);
+            }
+            let previous_size = self.memory.grow(added_pages)?;
+            faults.remaining_pages = Some(remaining_pages - added_pages);
+            return Ok(previous_size);
+        }
+        self.memory.grow(added_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.memory.read(offset, buf);
+        let end = offset + buf.len() as u64;
+        for (flip_offset, mask) in self.faults.borrow().flips.range(offset..end) {
+            buf[(flip_offset - offset) as usize] ^= mask;
+        }
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let mut faults = self.faults.borrow_mut();
+        let mut len = buf.len() as u64;
+        if let Some(cut_offset) = faults.cut_offset {
+            len = len.min(cut_offset.saturating_sub(offset));
+        }
+        if let Some(sector_size) = faults.tear_sector_size {
+            let halfway = sector_size / 2;
+            let offset_in_sector = offset % sector_size;
+            let tear_offset = if offset_in_sector < halfway {
+                offset - offset_in_sector + halfway
+            } else {
+                offset - offset_in_sector + sector_size + halfway
+            };
+            if tear_offset < offset + len {
+                len = tear_offset - offset;
+                faults.tear_sector_size = None;
+            }
+        }
+        self.memory.write(offset, &buf[..len as usize]);
+    }
+
+    fn flush(&self) {
+        self.memory.flush()
+    }
+}
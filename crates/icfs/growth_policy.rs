@@ -0,0 +1,33 @@
+use crate::internal::div_ceil;
+
+/// Decides how many pages to add when a write runs past the end of a memory.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum GrowthPolicy {
+    /// Adds exactly the pages needed for the write.
+    #[default]
+    Exact,
+    /// Adds the pages needed for the write, rounded up to a multiple of this many pages.
+    Chunk(u64),
+    /// Adds at least as many pages as the memory already has, doubling its size.
+    Geometric,
+}
+
+impl GrowthPolicy {
+    /// Gets the number of pages to add to a memory of `size` pages so that it
+    /// spans at least `pages_required` pages.
+    pub fn additional_pages(&self, size: u64, pages_required: u64) -> u64 {
+        let additional_pages_required = pages_required.saturating_sub(size);
+        if additional_pages_required == 0 {
+            return 0;
+        }
+        match *self {
+            GrowthPolicy::Exact => additional_pages_required,
+            GrowthPolicy::Chunk(chunk_pages) => {
+                let chunk_pages = chunk_pages.max(1);
+                div_ceil(additional_pages_required, chunk_pages)
+                    .saturating_mul(chunk_pages)
+            }
+            GrowthPolicy::Geometric => additional_pages_required.max(size),
+        }
+    }
+}
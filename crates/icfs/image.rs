@@ -0,0 +1,200 @@
+// Exports a memory as a sequence of bounded chunks, and imports it elsewhere.
+//
+// A whole memory is too large to copy in one message, both for the message
+// size limit and the instruction limit, so the image is split into chunks that
+// are sent one message at a time. Each chunk carries the SHA-256 of the image
+// up to its end, so that the importer can check every chunk as it arrives
+// rather than only once the whole image is in place. The state of either side
+// lives on the heap between messages.
+use crate::error::Error;
+use crate::internal::ensure_capacity;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::stable_memory::capacity;
+use sha2::{Digest, Sha256};
+
+/// A chunk of an image of a memory, produced by `ImageExport`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImageChunk {
+    /// The offset of the chunk within the image.
+    pub offset: u64,
+    /// The length of the whole image in bytes.
+    pub total_len: u64,
+    pub bytes: Vec<u8>,
+    /// The SHA-256 of the image from its start up to the end of the chunk.
+    pub sha256: [u8; 32],
+}
+
+/// Exports an image of a memory one chunk at a time.
+///
+/// The image spans the memory as it was when the export was created. Writes
+/// made to the memory while it is being exported may or may not be included,
+/// so hold them off until the export is done to get a consistent image.
+#[derive(Clone)]
+pub struct ImageExport<M: Memory = Ic0StableMemory> {
+    memory: M,
+    total_len: u64,
+    offset: u64,
+    sha256: Sha256,
+    // Kept so that it can be exported again if its reply was lost.
+    last_chunk: Option<ImageChunk>,
+}
+
+/// Imports an image of a memory one chunk at a time, in the order they were
+/// exported.
+///
+/// The memory is grown to fit the image when the first chunk is imported.
+/// Anything in the memory past the end of the image is left as it is, so
+/// import into an empty memory to get an exact copy.
+#[derive(Clone)]
+pub struct ImageImport<M: Memory = Ic0StableMemory> {
+    memory: M,
+    total_len: Option<u64>,
+    offset: u64,
+    sha256: Sha256,
+}
+
+impl<M: Memory> ImageExport<M> {
+    /// Creates an export of the whole of the given memory.
+    pub fn new(memory: M) -> Self {
+        let total_len = capacity(&memory);
+        Self {
+            memory,
+            total_len,
+            offset: 0,
+            sha256: Sha256::new(),
+            last_chunk: None,
+        }
+    }
+
+    /// Gets the length of the image in bytes.
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Gets the offset of the next chunk to be exported.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns `true` if every chunk has been exported.
+    pub fn is_done(&self) -> bool {
+        self.offset == self.total_len
+    }
+
+    /// Exports the next chunk of at most `max_len` bytes, or returns `None` if
+    /// every chunk has been exported.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is 0.
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<ImageChunk> {
+        assert!(max_len != 0, "Chunks must not be empty");
+        if self.is_done() {
+            return None;
+        }
+        let len = (self.total_len - self.offset).min(max_len as u64) as usize;
+        let mut bytes = vec![0; len];
+        self.memory.read(self.offset, &mut bytes);
+        self.sha256.update(&bytes);
+        let chunk = ImageChunk {
+            offset: self.offset,
+            total_len: self.total_len,
+            bytes,
+            sha256: self.sha256.clone().finalize().into(),
+        };
+        self.offset += len as u64;
+        self.last_chunk = Some(chunk.clone());
+        Some(chunk)
+    }
+
+    /// Exports the chunk at `offset`, of at most `max_len` bytes, or returns
+    /// `None` if `offset` is the end of the image.
+    ///
+    /// `offset` must either be the offset of the next chunk, or that of the
+    /// last chunk exported, which is then exported again as it was. This way
+    /// a chunk whose reply was lost can be asked for again. Any other offset
+    /// fails with `Error::UnexpectedChunk`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is 0.
+    pub fn export_chunk(&mut self, offset: u64, max_len: usize) -> Result<Option<ImageChunk>, Error> {
+        match &self.last_chunk {
+            Some(last_chunk) if last_chunk.offset == offset => Ok(Some(last_chunk.clone())),
+            _ if offset == self.offset => Ok(self.next_chunk(max_len)),
+            _ => Err(Error::UnexpectedChunk {
+                expected_offset: self.offset,
+            }),
+        }
+    }
+}
+
+impl<M: Memory> ImageImport<M> {
+    /// Creates an import into the given memory.
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            total_len: None,
+            offset: 0,
+            sha256: Sha256::new(),
+        }
+    }
+
+    /// Gets the length of the image in bytes, once the first chunk has been
+    /// imported.
+    pub fn total_len(&self) -> Option<u64> {
+        self.total_len
+    }
+
+    /// Gets the offset of the next chunk expected.
+    ///
+    /// An export interrupted on the other side can be resumed from here.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns `true` if every chunk of the image has been imported.
+    pub fn is_done(&self) -> bool {
+        self.total_len == Some(self.offset)
+    }
+
+    /// Checks a chunk against the chunks imported so far and writes it to the
+    /// memory.
+    ///
+    /// A chunk that does not follow on from the last one fails with
+    /// `Error::UnexpectedChunk`, and one whose hash does not match fails with
+    /// `Error::HashMismatch`. Either way, nothing is written and the import
+    /// can carry on with the right chunk.
+    pub fn import_chunk(&mut self, chunk: &ImageChunk) -> Result<(), Error> {
+        let unexpected = Error::UnexpectedChunk {
+            expected_offset: self.offset,
+        };
+        if chunk.offset != self.offset || matches!(self.total_len, Some(len) if len != chunk.total_len) {
+            return Err(unexpected);
+        }
+        let end = chunk
+            .offset
+            .checked_add(chunk.bytes.len() as u64)
+            .ok_or(unexpected)?;
+        if end > chunk.total_len {
+            return Err(unexpected);
+        }
+
+        let mut sha256 = self.sha256.clone();
+        sha256.update(&chunk.bytes);
+        if <[u8; 32]>::from(sha256.clone().finalize()) != chunk.sha256 {
+            return Err(Error::HashMismatch {
+                offset: chunk.offset,
+            });
+        }
+
+        if self.total_len.is_none() {
+            ensure_capacity(&self.memory, chunk.total_len)?;
+            self.total_len = Some(chunk.total_len);
+        }
+        self.memory.write(chunk.offset, &chunk.bytes);
+        self.sha256 = sha256;
+        self.offset = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+#[cfg(feature = "mock")]
+use crate::mock::instruction_counter;
+use crate::memory::{Ic0StableMemory, Memory, WASM_PAGE_SIZE_IN_BYTES};
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// The version of ic-cdk we depend on does not expose the performance counter,
+// so it is imported from the system API directly.
+#[cfg(all(not(feature = "mock"), target_arch = "wasm32"))]
+#[link(wasm_import_module = "ic0")]
+extern "C" {
+    fn performance_counter(counter_type: u32) -> u64;
+}
+
+/// Gets the number of instructions executed so far in the current message.
+#[cfg(all(not(feature = "mock"), target_arch = "wasm32"))]
+fn instruction_counter() -> u64 {
+    unsafe { performance_counter(0) }
+}
+
+/// There is no instruction counter outside of a canister.
+#[cfg(all(not(feature = "mock"), not(target_arch = "wasm32")))]
+fn instruction_counter() -> u64 {
+    0
+}
+
+/// Counts for one kind of call made to a memory.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OperationMetrics {
+    /// The number of calls made.
+    pub calls: u64,
+    /// The number of bytes read, written or added by the calls.
+    pub bytes: u64,
+    /// The number of instructions the calls took, as measured by the
+    /// instruction counter of the system API.
+    pub instructions: u64,
+}
+
+/// Counts for the calls made to an `InstrumentedMemory`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct IoMetrics {
+    pub reads: OperationMetrics,
+    pub writes: OperationMetrics,
+    /// Failed attempts to grow the memory are counted as calls, but add no
+    /// bytes.
+    pub grows: OperationMetrics,
+}
+
+impl IoMetrics {
+    /// Formats the metrics in the Prometheus text exposition format, with the
+    /// names of the metrics starting with `prefix`.
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut text = String::new();
+        self.write_counter(&mut text, prefix, "calls", "Number of calls made to the memory.", |metrics| {
+            metrics.calls
+        });
+        self.write_counter(&mut text, prefix, "bytes", "Number of bytes read, written or added.", |metrics| {
+            metrics.bytes
+        });
+        self.write_counter(
+            &mut text,
+            prefix,
+            "instructions",
+            "Number of instructions taken by calls to the memory.",
+            |metrics| metrics.instructions,
+        );
+        text
+    }
+
+    /// Writes one counter, with a sample for each kind of call.
+    fn write_counter(
+        &self,
+        text: &mut String,
+        prefix: &str,
+        name: &str,
+        help: &str,
+        value: impl Fn(&OperationMetrics) -> u64,
+    ) {
+        // Writing to a `String` cannot fail.
+        writeln!(text, "# HELP {}_{}_total {}", prefix, name, help).unwrap();
+        writeln!(text, "# TYPE {}_{}_total counter", prefix, name).unwrap();
+        for (operation, metrics) in [("read", &self.reads), ("write", &self.writes), ("grow", &self.grows)] {
+            writeln!(
+                text,
+                "{}_{}_total{{operation=\"{}\"}} {}",
+                prefix,
+                name,
+                operation,
+                value(metrics)
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// A memory that counts the calls made to the memory it wraps, the bytes they
+/// move and the instructions they take.
+///
+/// Wrapping `Ic0StableMemory` counts calls to the `stable64_*` system API.
+/// Clones share the same metrics.
+#[derive(Clone, Debug, Default)]
+pub struct InstrumentedMemory<M: Memory = Ic0StableMemory> {
+    memory: M,
+    metrics: Rc<RefCell<IoMetrics>>,
+}
+
+/// Runs `f`, returning its result along with the number of instructions it
+/// took.
+fn count_instructions<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    let start = instruction_counter();
+    let result = f();
+    (result, instruction_counter().saturating_sub(start))
+}
+
+impl OperationMetrics {
+    fn record(&mut self, bytes: u64, instructions: u64) {
+        self.calls += 1;
+        self.bytes += bytes;
+        self.instructions += instructions;
+    }
+}
+
+impl<M: Memory> InstrumentedMemory<M> {
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            metrics: Rc::new(RefCell::new(IoMetrics::default())),
+        }
+    }
+
+    /// Gets the metrics counted so far.
+    pub fn metrics(&self) -> IoMetrics {
+        *self.metrics.borrow()
+    }
+
+    /// Sets the metrics back to 0.
+    pub fn reset(&self) {
+        *self.metrics.borrow_mut() = IoMetrics::default()
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M: Memory> Memory for InstrumentedMemory<M> {
+    fn size(&self) -> u64 {
+        self.memory.size()
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        let (result, instructions) = count_instructions(|| self.memory.grow(added_pages));
+        let bytes = match result {
+            Ok(_) => added_pages.saturating_mul(WASM_PAGE_SIZE_IN_BYTES),
+            Err(_) => 0,
+        };
+        self.metrics.borrow_mut().grows.record(bytes, instructions);
+        result
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let bytes = buf.len() as u64;
+        let ((), instructions) = count_instructions(|| self.memory.read(offset, buf));
+        self.metrics.borrow_mut().reads.record(bytes, instructions)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let ((), instructions) = count_instructions(|| self.memory.write(offset, buf));
+        self.metrics
+            .borrow_mut()
+            .writes
+            .record(buf.len() as u64, instructions)
+    }
+
+    fn flush(&self) {
+        self.memory.flush()
+    }
+}
@@ -1,59 +1,207 @@
-// Copied from: https://github.com/rust-lang/rust/blob/a2af9cf1cf6ccb195eae40cdd793939bc77e7e73/library/std/src/io/mod.rs#L356
-// This may be avoidable in future: https://github.com/rust-lang/rfcs/pull/1210
-pub (crate) fn default_read_to_end<R: std::io::Read + ?Sized>(r: &mut R, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-    let start_len = buf.len();
-    let start_cap = buf.capacity();
-
-    let mut initialized = 0; // Extra initialized bytes from previous loop iteration
-    loop {
-        if buf.len() == buf.capacity() {
-            buf.reserve(32); // buf is full, need more space
+use crate::memory::{Memory, WASM_PAGE_SIZE_IN_BYTES};
+
+/// Moves `offset` by `delta` bytes, as when seeking.
+pub (crate) fn offset_by(offset: u64, delta: i64) -> Result<u64, crate::Error> {
+    if delta >= 0 {
+        offset.checked_add(delta as u64).ok_or(crate::Error::OffsetOverflow)
+    } else {
+        offset.checked_sub(delta.unsigned_abs()).ok_or(crate::Error::SeekBeforeStart)
+    }
+}
+
+/// Divides `n` by `d`, rounding up.
+pub (crate) fn div_ceil(n: u64, d: u64) -> u64 {
+    let remainder = n % d;
+    n / d + (remainder != 0) as u64
+}
+
+/// Grows the memory so that it spans at least `end` bytes.
+pub (crate) fn ensure_capacity<M: Memory>(memory: &M, end: u64) -> Result<(), crate::Error> {
+    let pages_required = div_ceil(end, WASM_PAGE_SIZE_IN_BYTES);
+    let available = memory.size();
+    let additional_pages_required = pages_required.saturating_sub(available);
+    if additional_pages_required > 0 {
+        memory
+            .grow(additional_pages_required)
+            .map_err(|_| crate::Error::GrowFailed {
+                requested: additional_pages_required,
+                available,
+            })?;
+    }
+    Ok(())
+}
+
+/// Reads data from the memory, filling in zeros for any part of `buf` that
+/// lies beyond its end.
+pub (crate) fn read_or_zero<M: Memory>(memory: &M, offset: u64, buf: &mut [u8]) {
+    let capacity = memory.size() * WASM_PAGE_SIZE_IN_BYTES;
+    let mapped_len = capacity.saturating_sub(offset).min(buf.len() as u64) as usize;
+    if mapped_len > 0 {
+        memory.read(offset, &mut buf[..mapped_len]);
+    }
+    buf[mapped_len..].iter_mut().for_each(|byte| *byte = 0);
+}
+
+/// A device made of fixed-size sectors that are each read and written as a
+/// whole, such as one whose sectors are checksummed, encrypted or compressed.
+///
+/// Only the sectors need implementing: `read_at` and `write_at` split reads
+/// and writes into sectors, and `impl_sector_device_io!` implements the
+/// positional and `std::io` traits on top of them.
+pub (crate) trait SectorDevice {
+    /// Gets the size of each sector in bytes.
+    fn sector_size(&self) -> u64;
+
+    /// Gets the number of sectors in the device.
+    fn num_sectors(&self) -> u64;
+
+    /// Reads the sector at `index`, which is `sector_size` bytes long.
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, crate::Error>;
+
+    /// Writes the sector at `index`, which is `sector_size` bytes long.
+    fn write_sector(&self, index: u64, sector: &[u8]) -> Result<(), crate::Error>;
+
+    /// Gets the length of the device in bytes.
+    fn len(&self) -> u64 {
+        self.num_sectors() * self.sector_size()
+    }
+}
+
+/// Gets the size of a table of `entry_size` bytes per sector in bytes, rounded
+/// up to a whole number of sectors.
+pub (crate) fn sector_table_size(sector_size: u64, num_sectors: u64, entry_size: u64) -> Option<u64> {
+    let size = num_sectors.checked_mul(entry_size)?;
+    let sectors = div_ceil(size, sector_size);
+    sectors.checked_mul(sector_size)
+}
+
+/// Checks that a device of `num_sectors` sectors of `sector_size` bytes, after
+/// a table of `table_size` bytes at `base`, ends at an offset addressable with
+/// 64 bits.
+///
+/// # Panics
+///
+/// Panics if it does not.
+pub (crate) fn assert_sectors_fit(base: u64, table_size: Option<u64>, sector_size: u64, num_sectors: u64) {
+    assert!(
+        table_size
+            .and_then(|table_size| base.checked_add(table_size))
+            .and_then(|sectors_offset| {
+                num_sectors
+                    .checked_mul(sector_size)
+                    .and_then(|len| sectors_offset.checked_add(len))
+            })
+            .is_some(),
+        "Region end overflows u64"
+    );
+}
+
+/// Reads data from the device location specified by an offset, reading every
+/// sector it touches.
+pub (crate) fn read_at<D: SectorDevice>(device: &D, offset: u64, buf: &mut [u8]) -> Result<usize, crate::Error> {
+    let len = device.len();
+    if offset > len {
+        return Err(crate::Error::OutOfBounds);
+    }
+    let read_len = (buf.len() as u64).min(len - offset) as usize;
+    let sector_size = device.sector_size();
+    let mut start = 0;
+    while start < read_len {
+        let position = offset + start as u64;
+        let offset_in_sector = (position % sector_size) as usize;
+        let chunk_len = (read_len - start).min(sector_size as usize - offset_in_sector);
+        let sector = device.read_sector(position / sector_size)?;
+        buf[start..start + chunk_len]
+            .copy_from_slice(&sector[offset_in_sector..offset_in_sector + chunk_len]);
+        start += chunk_len;
+    }
+    Ok(read_len)
+}
+
+/// Writes a byte slice to the device location specified by an offset.
+///
+/// Every sector written is written as a whole, so sectors that are only partly
+/// overwritten are read first. Writes are cut short at the end of the device.
+pub (crate) fn write_at<D: SectorDevice>(device: &D, offset: u64, buf: &[u8]) -> Result<usize, crate::Error> {
+    let len = device.len();
+    if offset > len {
+        return Err(crate::Error::OutOfBounds);
+    }
+    let write_len = (buf.len() as u64).min(len - offset) as usize;
+    let sector_size = device.sector_size();
+    let mut start = 0;
+    while start < write_len {
+        let position = offset + start as u64;
+        let index = position / sector_size;
+        let offset_in_sector = (position % sector_size) as usize;
+        let chunk_len = (write_len - start).min(sector_size as usize - offset_in_sector);
+        if chunk_len as u64 == sector_size {
+            device.write_sector(index, &buf[start..start + chunk_len])?;
+        } else {
+            let mut sector = device.read_sector(index)?;
+            sector[offset_in_sector..offset_in_sector + chunk_len]
+                .copy_from_slice(&buf[start..start + chunk_len]);
+            device.write_sector(index, &sector)?;
         }
+        start += chunk_len;
+    }
+    Ok(write_len)
+}
 
-        let mut read_buf = std::io::ReadBuf::uninit(buf.spare_capacity_mut());
+/// Moves the cursor of a device of `len` bytes, which is at `offset`.
+pub (crate) fn seek(len: u64, offset: u64, pos: std::io::SeekFrom) -> Result<u64, crate::Error> {
+    match pos {
+        std::io::SeekFrom::Start(start) => Ok(start),
+        std::io::SeekFrom::End(end) => offset_by(len, end),
+        std::io::SeekFrom::Current(current) => offset_by(offset, current),
+    }
+}
 
-        // SAFETY: These bytes were initialized but not filled in the previous loop
-        unsafe {
-            read_buf.assume_init(initialized);
+/// Implements `ReadAt`, `WriteAt`, `Read`, `Write` and `Seek` for a
+/// `SectorDevice` with `memory` and `offset` (its cursor) fields.
+macro_rules! impl_sector_device_io {
+    ($device:ident) => {
+        impl<M: crate::memory::Memory> crate::positioned::ReadAt for $device<M> {
+            fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+                crate::internal::read_at(self, offset, buf).map_err(std::io::Error::from)
+            }
         }
 
-        match r.read_buf(&mut read_buf) {
-            Ok(()) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
+        impl<M: crate::memory::Memory> crate::positioned::WriteAt for $device<M> {
+            fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+                crate::internal::write_at(self, offset, buf).map_err(std::io::Error::from)
+            }
         }
 
-        if read_buf.filled_len() == 0 {
-            return Ok(buf.len() - start_len);
+        impl<M: crate::memory::Memory> std::io::Read for $device<M> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read_len = crate::internal::read_at(self, self.offset, buf)?;
+                self.offset += read_len as u64;
+                Ok(read_len)
+            }
         }
 
-        // store how much was initialized but not filled
-        initialized = read_buf.initialized_len() - read_buf.filled_len();
-        let new_len = read_buf.filled_len() + buf.len();
+        impl<M: crate::memory::Memory> std::io::Write for $device<M> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let write_len = crate::internal::write_at(self, self.offset, buf)?;
+                self.offset += write_len as u64;
+                Ok(write_len)
+            }
 
-        // SAFETY: ReadBuf's invariants mean this much memory is init
-        unsafe {
-            buf.set_len(new_len);
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.memory.flush();
+                Ok(())
+            }
         }
 
-        if buf.len() == buf.capacity() && buf.capacity() == start_cap {
-            // The buffer might be an exact fit. Let's read into a probe buffer
-            // and see if it returns `Ok(0)`. If so, we've avoided an
-            // unnecessary doubling of the capacity. But if not, append the
-            // probe buffer to the primary buffer and let its capacity grow.
-            let mut probe = [0u8; 32];
-
-            loop {
-                match r.read(&mut probe) {
-                    Ok(0) => return Ok(buf.len() - start_len),
-                    Ok(n) => {
-                        buf.extend_from_slice(&probe[..n]);
-                        break;
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e),
-                }
+        impl<M: crate::memory::Memory> std::io::Seek for $device<M> {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                let len = crate::internal::SectorDevice::len(self);
+                self.offset = crate::internal::seek(len, self.offset, pos)?;
+                Ok(self.offset)
             }
         }
-    }
+    };
 }
+
+pub (crate) use impl_sector_device_io;
@@ -0,0 +1,276 @@
+// A write-ahead journal for transactions on a `StableMemory`.
+//
+// State is committed whenever a canister awaits an inter-canister call, so a
+// multi-step update that spans an await could otherwise be left half-written.
+// Writes made in a transaction are instead appended to the journal, and only
+// applied to the data once the transaction is committed. The journal is marked
+// as committed before its records are applied, so that if applying them does
+// not complete, they are applied again on recovery.
+//
+// Layout:
+// -------------------------------------------------- <- Byte 0
+// Magic "ICJ"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// State                                  ↕ 1 byte
+// Reserved space                         ↕ 3 bytes
+// Length of the records (in bytes)       ↕ 8 bytes
+// -------------------------------------------------- <- Byte 16
+// Offset of record 0 in the data         ↕ 8 bytes
+// Length of record 0 (in bytes)          ↕ 8 bytes
+// Bytes of record 0                      ↕ N bytes
+// Offset of record 1 in the data         ↕ 8 bytes
+// ...
+use crate::error::Error;
+use crate::internal::{ensure_capacity, read_or_zero};
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::stable_memory::{memory_offset, write_at, StableMemory};
+
+const MAGIC: &[u8; 3] = b"ICJ";
+const LAYOUT_VERSION: u8 = 1;
+
+const STATE_OFFSET: u64 = 4;
+const RECORDS_LEN_OFFSET: u64 = 8;
+const HEADER_SIZE_IN_BYTES: u64 = 16;
+const RECORD_HEADER_SIZE_IN_BYTES: u64 = 16;
+
+// The number of bytes copied at a time when applying a record.
+const APPLY_CHUNK_SIZE_IN_BYTES: u64 = 4096;
+
+const STATE_EMPTY: u8 = 0;
+const STATE_OPEN: u8 = 1;
+const STATE_COMMITTED: u8 = 2;
+
+/// Makes groups of writes to a `StableMemory` all-or-nothing, even when they
+/// span await points.
+///
+/// Writes made between `begin` and `commit` are recorded in a separate journal
+/// memory and only applied to the data on `commit`. Reads through the journal
+/// see the writes of the transaction in progress, while reads through any
+/// other handle see the data as it was before the transaction. Only one
+/// transaction can be in progress at a time.
+#[derive(Copy, Clone, Debug)]
+pub struct Journal<M: Memory = Ic0StableMemory> {
+    stable_memory: StableMemory<M>,
+    journal: M,
+}
+
+/// A write recorded in the journal.
+struct Record {
+    /// The offset of the write in the data.
+    offset: u64,
+    len: u64,
+    /// The offset of the bytes written in the journal.
+    journal_offset: u64,
+}
+
+fn get_state<M: Memory>(journal: &M) -> u8 {
+    let mut state = [0];
+    journal.read(STATE_OFFSET, &mut state);
+    state[0]
+}
+
+fn set_state<M: Memory>(journal: &M, state: u8) {
+    journal.write(STATE_OFFSET, &[state])
+}
+
+fn get_records_len<M: Memory>(journal: &M) -> u64 {
+    let mut bytes = [0; 8];
+    journal.read(RECORDS_LEN_OFFSET, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn set_records_len<M: Memory>(journal: &M, len: u64) {
+    journal.write(RECORDS_LEN_OFFSET, &len.to_le_bytes())
+}
+
+fn records<M: Memory>(journal: &M) -> Vec<Record> {
+    let end = HEADER_SIZE_IN_BYTES + get_records_len(journal);
+    let mut records = vec![];
+    let mut offset = HEADER_SIZE_IN_BYTES;
+    while offset < end {
+        let mut header = [0; RECORD_HEADER_SIZE_IN_BYTES as usize];
+        journal.read(offset, &mut header);
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&header[0..8]);
+        let data_offset = u64::from_le_bytes(bytes);
+        bytes.copy_from_slice(&header[8..16]);
+        let len = u64::from_le_bytes(bytes);
+        let journal_offset = offset + RECORD_HEADER_SIZE_IN_BYTES;
+        records.push(Record {
+            offset: data_offset,
+            len,
+            journal_offset,
+        });
+        offset = journal_offset + len;
+    }
+    records
+}
+
+/// Empties the journal, discarding its records.
+fn clear<M: Memory>(journal: &M) {
+    set_records_len(journal, 0);
+    set_state(journal, STATE_EMPTY);
+}
+
+/// Applies the records of a committed transaction to the data, then empties
+/// the journal.
+fn apply<M: Memory>(stable_memory: &StableMemory<M>, journal: &M) -> Result<(), Error> {
+    let mut buf = vec![0; APPLY_CHUNK_SIZE_IN_BYTES as usize];
+    for record in records(journal) {
+        let mut applied = 0;
+        while applied < record.len {
+            let len = (record.len - applied).min(APPLY_CHUNK_SIZE_IN_BYTES) as usize;
+            journal.read(record.journal_offset + applied, &mut buf[..len]);
+            let mut written = 0;
+            while written < len {
+                let offset = record.offset + applied + written as u64;
+                match write_at(stable_memory, offset, &buf[written..len])? {
+                    0 => return Err(Error::OutOfBounds),
+                    n => written += n,
+                }
+            }
+            applied += len as u64;
+        }
+    }
+    clear(journal);
+    Ok(())
+}
+
+impl<M: Memory> Journal<M> {
+    /// Loads the journal stored in `journal`, or creates a new one if
+    /// `journal` is empty, for transactions on `stable_memory`.
+    ///
+    /// Loading the journal recovers from any transaction left behind: one that
+    /// was committed is applied again, and one that was not is discarded, as
+    /// it can no longer be finished. Call this from `init` and `post_upgrade`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `journal` is not empty and does not hold a journal.
+    pub fn init(stable_memory: StableMemory<M>, journal: M) -> Result<Self, Error> {
+        if journal.size() == 0 {
+            ensure_capacity(&journal, HEADER_SIZE_IN_BYTES)?;
+            let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+            header[0..3].copy_from_slice(MAGIC);
+            header[3] = LAYOUT_VERSION;
+            header[4] = STATE_EMPTY;
+            journal.write(0, &header);
+        } else {
+            let mut header = [0; 4];
+            journal.read(0, &mut header);
+            assert_eq!(&header[0..3], MAGIC, "Memory does not hold a journal");
+            assert_eq!(header[3], LAYOUT_VERSION, "Unsupported journal layout version");
+            match get_state(&journal) {
+                STATE_COMMITTED => apply(&stable_memory, &journal)?,
+                STATE_OPEN => clear(&journal),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            stable_memory,
+            journal,
+        })
+    }
+
+    /// Returns `true` if a transaction has been begun but not yet ended.
+    pub fn in_transaction(&self) -> bool {
+        get_state(&self.journal) == STATE_OPEN
+    }
+
+    /// Begins a transaction.
+    ///
+    /// This fails while a committed transaction has yet to be fully applied.
+    pub fn begin(&self) -> Result<(), Error> {
+        if get_state(&self.journal) != STATE_EMPTY {
+            return Err(Error::TransactionInProgress);
+        }
+        set_state(&self.journal, STATE_OPEN);
+        Ok(())
+    }
+
+    /// Records a write of the whole of `buf` at `offset` in the data, to be
+    /// made when the transaction is committed.
+    pub fn write_all_at(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        if !self.in_transaction() {
+            return Err(Error::NoTransaction);
+        }
+        let len = buf.len() as u64;
+        let records_len = get_records_len(&self.journal);
+        let record_offset = HEADER_SIZE_IN_BYTES + records_len;
+        let new_records_len = records_len
+            .checked_add(RECORD_HEADER_SIZE_IN_BYTES + len)
+            .ok_or(Error::OffsetOverflow)?;
+        offset.checked_add(len).ok_or(Error::OffsetOverflow)?;
+        ensure_capacity(&self.journal, HEADER_SIZE_IN_BYTES + new_records_len)?;
+
+        let mut header = [0; RECORD_HEADER_SIZE_IN_BYTES as usize];
+        header[0..8].copy_from_slice(&offset.to_le_bytes());
+        header[8..16].copy_from_slice(&len.to_le_bytes());
+        self.journal.write(record_offset, &header);
+        self.journal
+            .write(record_offset + RECORD_HEADER_SIZE_IN_BYTES, buf);
+        set_records_len(&self.journal, new_records_len);
+        Ok(())
+    }
+
+    /// Reads data at `offset`, as it will be if the transaction in progress,
+    /// if any, is committed.
+    ///
+    /// Bytes that neither the data nor the transaction cover are read as
+    /// zeros, and the number of bytes up to the last one covered is returned.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        // The data past its end is all zeros, as truncating it zeroes the bytes
+        // cut off, so it can be read straight from the memory.
+        let mut read_len = self
+            .stable_memory
+            .len()
+            .saturating_sub(offset)
+            .min(buf.len() as u64) as usize;
+        read_or_zero(
+            self.stable_memory.memory(),
+            memory_offset(&self.stable_memory, offset)?,
+            buf,
+        );
+        if !self.in_transaction() {
+            return Ok(read_len);
+        }
+
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OffsetOverflow)?;
+        for record in records(&self.journal) {
+            let start = record.offset.max(offset);
+            let record_end = record.offset + record.len;
+            if start >= record_end.min(end) {
+                continue;
+            }
+            let len = (record_end.min(end) - start) as usize;
+            let buf_start = (start - offset) as usize;
+            self.journal.read(
+                record.journal_offset + (start - record.offset),
+                &mut buf[buf_start..buf_start + len],
+            );
+            read_len = read_len.max(buf_start + len);
+        }
+        Ok(read_len)
+    }
+
+    /// Commits the transaction in progress, applying its writes to the data.
+    ///
+    /// If applying the writes fails, the transaction remains committed, and
+    /// they are applied again when the journal is next loaded with `init`.
+    pub fn commit(&self) -> Result<(), Error> {
+        if !self.in_transaction() {
+            return Err(Error::NoTransaction);
+        }
+        set_state(&self.journal, STATE_COMMITTED);
+        apply(&self.stable_memory, &self.journal)
+    }
+
+    /// Aborts the transaction in progress, discarding its writes.
+    pub fn abort(&self) -> Result<(), Error> {
+        if !self.in_transaction() {
+            return Err(Error::NoTransaction);
+        }
+        clear(&self.journal);
+        Ok(())
+    }
+}
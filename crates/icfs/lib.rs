@@ -1,6 +1,58 @@
-#![feature(read_buf)]
+#![feature(can_vector)]
+#![feature(derive_default_enum)]
+#![feature(io_error_more)]
 #![feature(result_flattening)]
 
+mod allocator;
+mod block_cache;
+mod checksummed_region;
+mod chunks;
+mod compressed_memory;
+mod dirty_pages;
+mod encrypted_memory;
+mod error;
+mod faulty_memory;
+mod growth_policy;
+mod image;
+mod instrumented_memory;
 mod internal;
+mod journal;
+mod memory;
+mod memory_manager;
+#[cfg(feature = "mock")]
+pub mod mock;
+mod positioned;
+mod region;
+mod snapshot_memory;
+mod stable_btree_map;
 mod stable_memory;
+mod stable_vec;
+mod storable;
+mod superblock;
+mod upgrade;
+pub use allocator::Allocator;
+pub use block_cache::BlockCache;
+pub use checksummed_region::ChecksummedRegion;
+pub use chunks::Chunks;
+pub use compressed_memory::{CompressedMemory, CompressionStats};
+pub use dirty_pages::DirtyPages;
+pub use encrypted_memory::EncryptedMemory;
+pub use error::Error;
+pub use faulty_memory::FaultyMemory;
+pub use growth_policy::GrowthPolicy;
+pub use image::{ImageChunk, ImageExport, ImageImport};
+pub use instrumented_memory::{InstrumentedMemory, IoMetrics, OperationMetrics};
+pub use journal::Journal;
+pub use memory::{Ic0StableMemory, Memory, VecMemory, WASM_PAGE_SIZE_IN_BYTES};
+pub use memory_manager::{MemoryId, MemoryManager, VirtualMemory, MAX_NUM_MEMORIES};
+pub use positioned::{ReadAt, WriteAt};
+pub use region::Region;
+pub use snapshot_memory::{Snapshot, SnapshotId, SnapshotMemory};
+pub use stable_btree_map::StableBTreeMap;
 pub use stable_memory::StableMemory;
+pub use stable_vec::StableVec;
+pub use storable::Storable;
+pub use superblock::{
+    Migrations, Superblock, MAX_NUM_REGIONS, MAX_REGION_NAME_LEN, SUPERBLOCK_SIZE_IN_BYTES,
+};
+pub use upgrade::{stable_restore, stable_save, UpgradeReader, UpgradeWriter};
@@ -0,0 +1,101 @@
+#[cfg(feature = "mock")]
+use crate::mock::{stable64_grow, stable64_read, stable64_size, stable64_write};
+#[cfg(not(feature = "mock"))]
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+pub const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024; // 64KB
+
+/// A linear memory addressed in bytes and grown in WebAssembly pages.
+pub trait Memory {
+    /// Gets current size of the memory in WebAssembly pages.
+    fn size(&self) -> u64;
+
+    /// Attempts to grow the memory by adding new pages.
+    ///
+    /// Returns the previous size of the memory in WebAssembly pages.
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError>;
+
+    /// Reads data from the memory location specified by an offset.
+    fn read(&self, offset: u64, buf: &mut [u8]);
+
+    /// Writes data to the memory location specified by an offset.
+    fn write(&self, offset: u64, buf: &[u8]);
+
+    /// Writes any buffered data through to the memory underneath.
+    ///
+    /// Memories that write through directly have nothing to do.
+    fn flush(&self) {}
+}
+
+/// The stable memory of the canister, accessed through the `stable64_*` system API.
+///
+/// With the `mock` feature enabled, a simulated stable memory is used instead.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Ic0StableMemory;
+
+impl Memory for Ic0StableMemory {
+    fn size(&self) -> u64 {
+        stable64_size()
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        stable64_grow(added_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable64_read(offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        stable64_write(offset, buf)
+    }
+}
+
+/// A memory backed by a vector on the heap.
+///
+/// Clones share the same underlying bytes, so this can be used in place of
+/// stable memory outside of a canister (e.g. in tests).
+#[derive(Clone, Debug, Default)]
+pub struct VecMemory {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl VecMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        self.bytes.borrow().len() as u64 / WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        let mut bytes = self.bytes.borrow_mut();
+        let previous_size = bytes.len() as u64 / WASM_PAGE_SIZE_IN_BYTES;
+        let new_len = previous_size
+            .checked_add(added_pages)
+            .and_then(|pages| pages.checked_mul(WASM_PAGE_SIZE_IN_BYTES))
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or(StableMemoryError::OutOfMemory)?;
+        bytes.resize(new_len, 0);
+        Ok(previous_size)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let bytes = self.bytes.borrow();
+        let start = offset as usize;
+        buf.copy_from_slice(&bytes[start..start + buf.len()]);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let mut bytes = self.bytes.borrow_mut();
+        let start = offset as usize;
+        bytes[start..start + buf.len()].copy_from_slice(buf);
+    }
+}
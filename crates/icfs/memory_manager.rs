@@ -0,0 +1,316 @@
+// Multiplexes several virtual memories onto a single memory.
+//
+// The underlying memory is divided into buckets of a fixed number of pages,
+// which are handed out to virtual memories as they grow. A header in the first
+// page records which virtual memory owns each bucket, so the layout survives
+// upgrades.
+//
+// Layout:
+// -------------------------------------------------- <- Page 0
+// Magic "ICM"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Number of allocated buckets            ↕ 2 bytes
+// Bucket size (in pages)                 ↕ 2 bytes
+// Reserved space                         ↕ 32 bytes
+// Size of memory 0 (in pages)            ↕ 8 bytes
+// ...
+// Size of memory 254 (in pages)          ↕ 8 bytes
+// Owner of bucket 0                      ↕ 1 byte
+// ...
+// Owner of bucket 32767                  ↕ 1 byte
+// -------------------------------------------------- <- Page 1
+// Bucket 0                               ↕ N pages
+// -------------------------------------------------- <- Page 1 + N
+// Bucket 1                               ↕ N pages
+// ...
+use crate::internal::div_ceil;
+use crate::memory::{Memory, WASM_PAGE_SIZE_IN_BYTES};
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 3] = b"ICM";
+const LAYOUT_VERSION: u8 = 1;
+
+/// The maximum number of virtual memories a manager can hand out.
+pub const MAX_NUM_MEMORIES: u8 = 255;
+
+const MAX_NUM_BUCKETS: u64 = 32768;
+const DEFAULT_BUCKET_SIZE_IN_PAGES: u16 = 128;
+const UNALLOCATED_BUCKET: u8 = MAX_NUM_MEMORIES;
+
+const HEADER_RESERVED_BYTES: u64 = 32;
+const HEADER_SIZE_IN_PAGES: u64 = 1;
+const MEMORY_SIZES_OFFSET: u64 = 8 + HEADER_RESERVED_BYTES;
+const BUCKET_TABLE_OFFSET: u64 = MEMORY_SIZES_OFFSET + MAX_NUM_MEMORIES as u64 * 8;
+
+/// Identifies one of the virtual memories of a `MemoryManager`.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MemoryId(u8);
+
+impl MemoryId {
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than `MAX_NUM_MEMORIES`.
+    pub const fn new(id: u8) -> Self {
+        assert!(id < MAX_NUM_MEMORIES);
+        Self(id)
+    }
+}
+
+struct MemoryManagerInner<M: Memory> {
+    memory: M,
+    bucket_size_in_pages: u16,
+    num_allocated_buckets: u16,
+    memory_sizes_in_pages: Vec<u64>,
+    memory_buckets: Vec<Vec<u16>>,
+}
+
+/// Hands out virtual memories that share a single underlying memory.
+pub struct MemoryManager<M: Memory> {
+    inner: Rc<RefCell<MemoryManagerInner<M>>>,
+}
+
+/// A memory handed out by a `MemoryManager`.
+///
+/// Each virtual memory starts out empty and grows independently of the others.
+pub struct VirtualMemory<M: Memory> {
+    id: MemoryId,
+    inner: Rc<RefCell<MemoryManagerInner<M>>>,
+}
+
+impl<M: Memory> MemoryManager<M> {
+    /// Loads the memory manager stored in `memory`, or creates a new one if
+    /// `memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` is not empty and does not hold a memory manager.
+    pub fn init(memory: M) -> Self {
+        Self::init_with_bucket_size(memory, DEFAULT_BUCKET_SIZE_IN_PAGES)
+    }
+
+    /// Like `init`, but uses buckets of `bucket_size_in_pages` pages if a new
+    /// memory manager is created.
+    ///
+    /// The bucket size of an existing memory manager is read from its header.
+    pub fn init_with_bucket_size(memory: M, bucket_size_in_pages: u16) -> Self {
+        assert!(bucket_size_in_pages > 0, "Bucket size must not be zero");
+        let inner = if memory.size() == 0 {
+            MemoryManagerInner::new(memory, bucket_size_in_pages)
+        } else {
+            MemoryManagerInner::load(memory)
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Returns the virtual memory with the given id.
+    pub fn get(&self, id: MemoryId) -> VirtualMemory<M> {
+        VirtualMemory {
+            id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> MemoryManagerInner<M> {
+    fn new(memory: M, bucket_size_in_pages: u16) -> Self {
+        memory
+            .grow(HEADER_SIZE_IN_PAGES)
+            .expect("Unable to grow memory for the memory manager header");
+
+        let inner = Self {
+            memory,
+            bucket_size_in_pages,
+            num_allocated_buckets: 0,
+            memory_sizes_in_pages: vec![0; MAX_NUM_MEMORIES as usize],
+            memory_buckets: vec![vec![]; MAX_NUM_MEMORIES as usize],
+        };
+
+        let mut header = [0; 8];
+        header[0..3].copy_from_slice(MAGIC);
+        header[3] = LAYOUT_VERSION;
+        header[4..6].copy_from_slice(&inner.num_allocated_buckets.to_le_bytes());
+        header[6..8].copy_from_slice(&inner.bucket_size_in_pages.to_le_bytes());
+        inner.memory.write(0, &header);
+        inner.memory.write(
+            BUCKET_TABLE_OFFSET,
+            &vec![UNALLOCATED_BUCKET; MAX_NUM_BUCKETS as usize],
+        );
+
+        inner
+    }
+
+    fn load(memory: M) -> Self {
+        let mut header = [0; 8];
+        memory.read(0, &mut header);
+        assert_eq!(
+            &header[0..3],
+            MAGIC,
+            "Memory does not hold a memory manager"
+        );
+        assert_eq!(
+            header[3], LAYOUT_VERSION,
+            "Unsupported memory manager layout version"
+        );
+        let num_allocated_buckets = u16::from_le_bytes([header[4], header[5]]);
+        let bucket_size_in_pages = u16::from_le_bytes([header[6], header[7]]);
+
+        let mut sizes = vec![0; MAX_NUM_MEMORIES as usize * 8];
+        memory.read(MEMORY_SIZES_OFFSET, &mut sizes);
+        let memory_sizes_in_pages = sizes
+            .chunks_exact(8)
+            .map(|size| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(size);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+
+        let mut owners = vec![0; num_allocated_buckets as usize];
+        memory.read(BUCKET_TABLE_OFFSET, &mut owners);
+        let mut memory_buckets = vec![vec![]; MAX_NUM_MEMORIES as usize];
+        for (bucket, owner) in owners.into_iter().enumerate() {
+            if owner != UNALLOCATED_BUCKET {
+                memory_buckets[owner as usize].push(bucket as u16);
+            }
+        }
+
+        Self {
+            memory,
+            bucket_size_in_pages,
+            num_allocated_buckets,
+            memory_sizes_in_pages,
+            memory_buckets,
+        }
+    }
+
+    fn bucket_size_in_bytes(&self) -> u64 {
+        self.bucket_size_in_pages as u64 * WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    fn grow(&mut self, id: MemoryId, added_pages: u64) -> Result<u64, StableMemoryError> {
+        let id = id.0 as usize;
+        let previous_size = self.memory_sizes_in_pages[id];
+        let new_size = previous_size
+            .checked_add(added_pages)
+            .ok_or(StableMemoryError::OutOfMemory)?;
+
+        let bucket_size_in_pages = self.bucket_size_in_pages as u64;
+        let buckets_required = div_ceil(new_size, bucket_size_in_pages);
+        let additional_buckets_required =
+            buckets_required.saturating_sub(self.memory_buckets[id].len() as u64);
+        let num_allocated_buckets = self.num_allocated_buckets as u64 + additional_buckets_required;
+        if num_allocated_buckets > MAX_NUM_BUCKETS {
+            return Err(StableMemoryError::OutOfMemory);
+        }
+
+        let pages_required = HEADER_SIZE_IN_PAGES + num_allocated_buckets * bucket_size_in_pages;
+        let additional_pages_required = pages_required.saturating_sub(self.memory.size());
+        if additional_pages_required > 0 {
+            self.memory.grow(additional_pages_required)?;
+        }
+
+        for _ in 0..additional_buckets_required {
+            let bucket = self.num_allocated_buckets;
+            self.memory
+                .write(BUCKET_TABLE_OFFSET + bucket as u64, &[id as u8]);
+            self.memory_buckets[id].push(bucket);
+            self.num_allocated_buckets += 1;
+        }
+        self.memory
+            .write(4, &self.num_allocated_buckets.to_le_bytes());
+
+        self.memory_sizes_in_pages[id] = new_size;
+        self.memory
+            .write(MEMORY_SIZES_OFFSET + id as u64 * 8, &new_size.to_le_bytes());
+
+        Ok(previous_size)
+    }
+
+    /// Maps a range of a virtual memory onto the underlying memory, splitting
+    /// it wherever it crosses a bucket boundary.
+    ///
+    /// Returns the offset in the underlying memory of each segment along with
+    /// the part of the range that it covers.
+    fn segments(&self, id: MemoryId, offset: u64, len: usize) -> Vec<(u64, Range<usize>)> {
+        let id = id.0 as usize;
+        let size_in_bytes = self.memory_sizes_in_pages[id] * WASM_PAGE_SIZE_IN_BYTES;
+        assert!(
+            matches!(offset.checked_add(len as u64), Some(end) if end <= size_in_bytes),
+            "Virtual memory access out of bounds"
+        );
+
+        let bucket_size_in_bytes = self.bucket_size_in_bytes();
+        let buckets_offset = HEADER_SIZE_IN_PAGES * WASM_PAGE_SIZE_IN_BYTES;
+        let mut segments = vec![];
+        let mut start = 0;
+        while start < len {
+            let virtual_offset = offset + start as u64;
+            let bucket_index = (virtual_offset / bucket_size_in_bytes) as usize;
+            let offset_in_bucket = virtual_offset % bucket_size_in_bytes;
+            let segment_len =
+                ((len - start) as u64).min(bucket_size_in_bytes - offset_in_bucket) as usize;
+            let bucket = self.memory_buckets[id][bucket_index] as u64;
+            segments.push((
+                buckets_offset + bucket * bucket_size_in_bytes + offset_in_bucket,
+                start..start + segment_len,
+            ));
+            start += segment_len;
+        }
+        segments
+    }
+}
+
+impl<M: Memory> VirtualMemory<M> {
+    /// Gets the id of this virtual memory within its manager.
+    pub fn id(&self) -> MemoryId {
+        self.id
+    }
+}
+
+impl<M: Memory> Clone for VirtualMemory<M> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> Memory for VirtualMemory<M> {
+    fn size(&self) -> u64 {
+        self.inner.borrow().memory_sizes_in_pages[self.id.0 as usize]
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        self.inner.borrow_mut().grow(self.id, added_pages)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being read is beyond the size of the virtual memory.
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let inner = self.inner.borrow();
+        for (physical_offset, range) in inner.segments(self.id, offset, buf.len()) {
+            inner.memory.read(physical_offset, &mut buf[range]);
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being written is beyond the size of the virtual memory.
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let inner = self.inner.borrow();
+        for (physical_offset, range) in inner.segments(self.id, offset, buf.len()) {
+            inner.memory.write(physical_offset, &buf[range]);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.borrow().memory.flush()
+    }
+}
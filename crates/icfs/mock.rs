@@ -0,0 +1,107 @@
+// Simulates the `stable64_*` system API in-process, so that code using
+// `Ic0StableMemory` can run outside of a replica (e.g. in `cargo test`).
+//
+// Each thread has its own simulated stable memory.
+use crate::memory::WASM_PAGE_SIZE_IN_BYTES;
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+
+/// The default maximum size of the simulated stable memory in pages (8GB).
+pub const DEFAULT_MAX_PAGES: u64 = 128 * 1024;
+
+struct MockStableMemory {
+    bytes: Vec<u8>,
+    max_pages: u64,
+    grow_failures: u64,
+}
+
+impl Default for MockStableMemory {
+    fn default() -> Self {
+        Self {
+            bytes: vec![],
+            max_pages: DEFAULT_MAX_PAGES,
+            grow_failures: 0,
+        }
+    }
+}
+
+thread_local! {
+    static STABLE_MEMORY: RefCell<MockStableMemory> = RefCell::new(MockStableMemory::default());
+}
+
+/// Empties the simulated stable memory and restores its default configuration.
+pub fn reset() {
+    STABLE_MEMORY.with(|stable_memory| *stable_memory.borrow_mut() = MockStableMemory::default())
+}
+
+/// Sets the number of pages beyond which the simulated stable memory cannot grow.
+pub fn set_max_pages(max_pages: u64) {
+    STABLE_MEMORY.with(|stable_memory| stable_memory.borrow_mut().max_pages = max_pages)
+}
+
+/// Makes the next `count` attempts to grow the simulated stable memory fail.
+pub fn fail_next_grows(count: u64) {
+    STABLE_MEMORY.with(|stable_memory| stable_memory.borrow_mut().grow_failures = count)
+}
+
+/// Gets current size of the simulated stable memory in WebAssembly pages.
+pub fn stable64_size() -> u64 {
+    STABLE_MEMORY.with(|stable_memory| {
+        stable_memory.borrow().bytes.len() as u64 / WASM_PAGE_SIZE_IN_BYTES
+    })
+}
+
+/// Attempts to grow the simulated stable memory by adding new pages.
+pub fn stable64_grow(new_pages: u64) -> Result<u64, StableMemoryError> {
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = stable_memory.borrow_mut();
+        if stable_memory.grow_failures > 0 {
+            stable_memory.grow_failures -= 1;
+            return Err(StableMemoryError::OutOfMemory);
+        }
+        let previous_size = stable_memory.bytes.len() as u64 / WASM_PAGE_SIZE_IN_BYTES;
+        match previous_size.checked_add(new_pages) {
+            Some(new_size) if new_size <= stable_memory.max_pages => {
+                let new_len = (new_size * WASM_PAGE_SIZE_IN_BYTES) as usize;
+                stable_memory.bytes.resize(new_len, 0);
+                Ok(previous_size)
+            }
+            _ => Err(StableMemoryError::OutOfMemory),
+        }
+    })
+}
+
+/// Reads data from the simulated stable memory location specified by an offset.
+///
+/// # Panics
+///
+/// Panics where the system API would trap, i.e. if the range being read is
+/// beyond the size of the simulated stable memory.
+pub fn stable64_read(offset: u64, buf: &mut [u8]) {
+    STABLE_MEMORY.with(|stable_memory| {
+        let stable_memory = stable_memory.borrow();
+        let start = offset as usize;
+        buf.copy_from_slice(&stable_memory.bytes[start..start + buf.len()]);
+    })
+}
+
+/// Writes data to the simulated stable memory location specified by an offset.
+///
+/// # Panics
+///
+/// Panics where the system API would trap, i.e. if the range being written is
+/// beyond the size of the simulated stable memory.
+pub fn stable64_write(offset: u64, buf: &[u8]) {
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = stable_memory.borrow_mut();
+        let start = offset as usize;
+        stable_memory.bytes[start..start + buf.len()].copy_from_slice(buf);
+    })
+}
+
+/// Simulates the instruction counter of the system API.
+///
+/// There are no instructions to count outside of a replica, so this is always 0.
+pub fn instruction_counter() -> u64 {
+    0
+}
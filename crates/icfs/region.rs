@@ -0,0 +1,141 @@
+use crate::error::Error;
+use crate::internal::{ensure_capacity, offset_by, read_or_zero};
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::positioned::{ReadAt, WriteAt};
+use std::io;
+
+/// A fixed-size window onto a memory, starting at `base` and spanning `len` bytes.
+///
+/// Offsets are relative to `base`, and reads and writes never touch bytes
+/// outside of the window. The underlying memory is grown on demand when
+/// writing, and any part of the window that lies beyond it reads as zeros.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Region<M: Memory = Ic0StableMemory> {
+    memory: M,
+    base: u64,
+    len: u64,
+    offset: u64,
+}
+
+/// Reads data from the region location specified by an offset.
+fn read_at<M: Memory>(region: &Region<M>, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    if offset > region.len {
+        return Err(Error::OutOfBounds);
+    }
+    let read_len = (buf.len() as u64).min(region.len - offset) as usize;
+    read_or_zero(&region.memory, region.base + offset, &mut buf[..read_len]);
+    Ok(read_len)
+}
+
+/// Reads data from the region location specified by its cursor.
+fn read<M: Memory>(region: &mut Region<M>, buf: &mut [u8]) -> Result<usize, Error> {
+    let read_len = read_at(region, region.offset, buf)?;
+    region.offset += read_len as u64;
+    Ok(read_len)
+}
+
+fn seek<M: Memory>(region: &mut Region<M>, pos: io::SeekFrom) -> Result<u64, Error> {
+    let new_offset = match pos {
+        io::SeekFrom::Start(start) => start,
+        io::SeekFrom::End(end) => offset_by(region.len, end)?,
+        io::SeekFrom::Current(current) => offset_by(region.offset, current)?,
+    };
+    region.offset = new_offset;
+    Ok(new_offset)
+}
+
+/// Writes a byte slice to the region location specified by an offset.
+///
+/// Writes are cut short at the end of the region.
+fn write_at<M: Memory>(region: &Region<M>, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+    if offset > region.len {
+        return Err(Error::OutOfBounds);
+    }
+    let write_len = (buf.len() as u64).min(region.len - offset) as usize;
+    let write_buf = &buf[..write_len];
+    let start = region.base + offset;
+    ensure_capacity(&region.memory, start + write_len as u64)?;
+    region.memory.write(start, write_buf);
+    Ok(write_len)
+}
+
+/// Writes a byte slice to the region location specified by its cursor.
+///
+/// Writes are cut short at the end of the region.
+fn write<M: Memory>(region: &mut Region<M>, buf: &[u8]) -> Result<usize, Error> {
+    let write_len = write_at(region, region.offset, buf)?;
+    region.offset += write_len as u64;
+    Ok(write_len)
+}
+
+impl<M: Memory> Region<M> {
+    /// Creates a region of `len` bytes starting at `base` in the given memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the end of the region is not addressable with 64 bits.
+    pub fn new(memory: M, base: u64, len: u64) -> Self {
+        assert!(base.checked_add(len).is_some(), "Region end overflows u64");
+        Self {
+            memory,
+            base,
+            len,
+            offset: 0,
+        }
+    }
+
+    /// Gets the offset of the region within the underlying memory.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Gets the length of the region in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the region spans no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M: Memory> std::io::Read for Region<M> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        read(self, buf).map_err(io::Error::from)
+    }
+}
+
+impl<M: Memory> std::io::Write for Region<M> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        write(self, buf).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.memory.flush();
+        Ok(())
+    }
+}
+
+impl<M: Memory> ReadAt for Region<M> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        read_at(self, offset, buf).map_err(io::Error::from)
+    }
+}
+
+impl<M: Memory> WriteAt for Region<M> {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        write_at(self, offset, buf).map_err(io::Error::from)
+    }
+}
+
+impl<M: Memory> std::io::Seek for Region<M> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        seek(self, pos).map_err(io::Error::from)
+    }
+}
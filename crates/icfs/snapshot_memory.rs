@@ -0,0 +1,485 @@
+// A memory that can take copy-on-write snapshots of itself.
+//
+// Each page of the live memory is mapped to a physical page of the underlying
+// memory by a table in the header. Taking a snapshot copies the table, so that
+// the snapshot shares every physical page with the live memory. Writing to a
+// shared page first moves the live page to a fresh physical page, leaving the
+// old one to the snapshots. Releasing the last snapshot that shares a page the
+// live memory has moved away from frees the page for reuse.
+//
+// So that writing to the live memory never needs to grow the underlying memory
+// to copy a page, a snapshot reserves a free page for each page it shares with
+// the live memory up front.
+//
+// Only the table of the live memory is persisted. Snapshots live on the heap
+// and so do not survive upgrades, and pages that only they used are freed when
+// the memory is loaded again.
+//
+// Layout:
+// -------------------------------------------------- <- Page 0
+// Magic "ICS"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Reserved space                         ↕ 4 bytes
+// Size of the live memory (in pages)     ↕ 8 bytes
+// Physical page of live page 0           ↕ 4 bytes
+// ...
+// Physical page of live page 65531       ↕ 4 bytes
+// -------------------------------------------------- <- Page 4
+// Physical page 0                        ↕ 1 page
+// -------------------------------------------------- <- Page 5
+// Physical page 1                        ↕ 1 page
+// ...
+use crate::error::Error;
+use crate::memory::{Memory, WASM_PAGE_SIZE_IN_BYTES};
+use ic_cdk::api::stable::StableMemoryError;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 3] = b"ICS";
+const LAYOUT_VERSION: u8 = 1;
+
+const HEADER_SIZE_IN_PAGES: u64 = 4;
+const SIZE_OFFSET: u64 = 8;
+const PAGE_TABLE_OFFSET: u64 = 16;
+const PAGE_TABLE_ENTRY_SIZE_IN_BYTES: u64 = 4;
+
+/// The maximum size of the live memory in pages.
+const MAX_NUM_PAGES: u64 = (HEADER_SIZE_IN_PAGES * WASM_PAGE_SIZE_IN_BYTES - PAGE_TABLE_OFFSET)
+    / PAGE_TABLE_ENTRY_SIZE_IN_BYTES;
+
+/// Identifies a snapshot taken by a `SnapshotMemory`.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SnapshotId(pub u64);
+
+struct SnapshotMemoryInner<M: Memory> {
+    memory: M,
+    /// The physical page of each page of the live memory.
+    live_pages: Vec<u32>,
+    /// The physical page of each page of each snapshot, as they were when the
+    /// snapshot was taken.
+    snapshots: BTreeMap<SnapshotId, Vec<u32>>,
+    next_snapshot_id: u64,
+    /// The number of snapshots sharing each physical page, for the pages
+    /// shared by at least one.
+    shared_pages: BTreeMap<u32, u64>,
+    free_pages: Vec<u32>,
+}
+
+/// A memory whose contents can be captured in snapshots, without copying them
+/// up front.
+///
+/// This is the live memory: reads and writes go to its current contents, while
+/// the snapshots taken with `snapshot` keep seeing the contents as they were.
+///
+/// The live memory can grow to at most 65532 pages, as that is as many as its
+/// page table holds. Growing it any further fails.
+pub struct SnapshotMemory<M: Memory> {
+    inner: Rc<RefCell<SnapshotMemoryInner<M>>>,
+}
+
+/// A read-only view of a `SnapshotMemory` as it was when a snapshot was taken.
+///
+/// Writing to it panics, and so does accessing it after the snapshot has been
+/// released.
+pub struct Snapshot<M: Memory> {
+    id: SnapshotId,
+    inner: Rc<RefCell<SnapshotMemoryInner<M>>>,
+}
+
+impl<M: Memory> SnapshotMemory<M> {
+    /// Loads the snapshot memory stored in `memory`, or creates a new one if
+    /// `memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` is not empty and does not hold a snapshot memory.
+    pub fn init(memory: M) -> Self {
+        let inner = if memory.size() == 0 {
+            SnapshotMemoryInner::new(memory)
+        } else {
+            SnapshotMemoryInner::load(memory)
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Takes a snapshot of the live memory, returning its id.
+    ///
+    /// No data is copied until the live memory is next written to, and then
+    /// only the pages written to are. The underlying memory is grown up front
+    /// though, so that there is a free page to copy each shared page to.
+    ///
+    /// Returns `Error::GrowFailed` if the underlying memory cannot grow enough.
+    pub fn snapshot(&self) -> Result<SnapshotId, Error> {
+        self.inner.borrow_mut().snapshot()
+    }
+
+    /// Returns a view of the snapshot with the given id, or `None` if there is
+    /// no such snapshot.
+    pub fn get(&self, id: SnapshotId) -> Option<Snapshot<M>> {
+        if !self.inner.borrow().snapshots.contains_key(&id) {
+            return None;
+        }
+        Some(Snapshot {
+            id,
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Releases the snapshot with the given id, freeing the pages that only it
+    /// was using.
+    ///
+    /// Returns `false` if there is no such snapshot, e.g. because it has
+    /// already been released.
+    pub fn release(&self, id: SnapshotId) -> bool {
+        self.inner.borrow_mut().release(id)
+    }
+
+    /// Gets the ids of the snapshots that have not been released.
+    pub fn snapshots(&self) -> Vec<SnapshotId> {
+        self.inner.borrow().snapshots.keys().copied().collect()
+    }
+
+    /// Gets the number of pages of the underlying memory that are free for
+    /// reuse.
+    pub fn num_free_pages(&self) -> u64 {
+        self.inner.borrow().free_pages.len() as u64
+    }
+}
+
+impl<M: Memory> SnapshotMemoryInner<M> {
+    fn new(memory: M) -> Self {
+        memory
+            .grow(HEADER_SIZE_IN_PAGES)
+            .expect("Unable to grow memory for the snapshot memory header");
+
+        let mut header = [0; PAGE_TABLE_OFFSET as usize];
+        header[0..3].copy_from_slice(MAGIC);
+        header[3] = LAYOUT_VERSION;
+        memory.write(0, &header);
+
+        Self {
+            memory,
+            live_pages: vec![],
+            snapshots: BTreeMap::new(),
+            next_snapshot_id: 0,
+            shared_pages: BTreeMap::new(),
+            free_pages: vec![],
+        }
+    }
+
+    fn load(memory: M) -> Self {
+        let mut header = [0; PAGE_TABLE_OFFSET as usize];
+        memory.read(0, &mut header);
+        assert_eq!(
+            &header[0..3],
+            MAGIC,
+            "Memory does not hold a snapshot memory"
+        );
+        assert_eq!(
+            header[3], LAYOUT_VERSION,
+            "Unsupported snapshot memory layout version"
+        );
+        let mut size = [0; 8];
+        size.copy_from_slice(&header[SIZE_OFFSET as usize..PAGE_TABLE_OFFSET as usize]);
+        let size = u64::from_le_bytes(size);
+
+        let mut table = vec![0; (size * PAGE_TABLE_ENTRY_SIZE_IN_BYTES) as usize];
+        memory.read(PAGE_TABLE_OFFSET, &mut table);
+        let live_pages: Vec<u32> = table
+            .chunks_exact(PAGE_TABLE_ENTRY_SIZE_IN_BYTES as usize)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect();
+
+        // Every physical page that the live memory does not use was only used
+        // by snapshots, which are gone.
+        let num_physical_pages = memory.size() - HEADER_SIZE_IN_PAGES;
+        let mut used = vec![false; num_physical_pages as usize];
+        for page in &live_pages {
+            used[*page as usize] = true;
+        }
+        let free_pages = (0..num_physical_pages as u32)
+            .filter(|page| !used[*page as usize])
+            .collect();
+
+        Self {
+            memory,
+            live_pages,
+            snapshots: BTreeMap::new(),
+            next_snapshot_id: 0,
+            shared_pages: BTreeMap::new(),
+            free_pages,
+        }
+    }
+
+    /// Gets the number of free pages kept for copying the live pages that are
+    /// shared with a snapshot.
+    fn num_reserved_pages(&self) -> usize {
+        self.live_pages
+            .iter()
+            .filter(|page| self.shared_pages.contains_key(page))
+            .count()
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId, Error> {
+        // Every live page is shared once the snapshot is taken.
+        let missing_pages = self.live_pages.len().saturating_sub(self.free_pages.len()) as u64;
+        if missing_pages > 0 {
+            let available = self.memory.size();
+            let first_page = u32::try_from(available - HEADER_SIZE_IN_PAGES)
+                .ok()
+                .filter(|page| page.checked_add(missing_pages as u32).is_some());
+            let grow_failed = Error::GrowFailed {
+                requested: missing_pages,
+                available,
+            };
+            let first_page = first_page.ok_or(grow_failed)?;
+            self.memory.grow(missing_pages).map_err(|_| grow_failed)?;
+            self.free_pages
+                .extend((0..missing_pages as u32).map(|page| first_page + page));
+        }
+
+        let id = SnapshotId(self.next_snapshot_id);
+        self.next_snapshot_id += 1;
+        for page in &self.live_pages {
+            *self.shared_pages.entry(*page).or_insert(0) += 1;
+        }
+        self.snapshots.insert(id, self.live_pages.clone());
+        Ok(id)
+    }
+
+    fn release(&mut self, id: SnapshotId) -> bool {
+        let pages = match self.snapshots.remove(&id) {
+            Some(pages) => pages,
+            None => return false,
+        };
+        for (index, page) in pages.into_iter().enumerate() {
+            let count = self
+                .shared_pages
+                .get_mut(&page)
+                .expect("Snapshot page is not shared");
+            *count -= 1;
+            if *count == 0 {
+                self.shared_pages.remove(&page);
+                // A physical page only ever backs the same page index, so the
+                // live memory is still using it if it is there.
+                if self.live_pages.get(index) != Some(&page) {
+                    self.free_pages.push(page);
+                }
+            }
+        }
+        true
+    }
+
+    fn physical_offset(page: u32) -> u64 {
+        (HEADER_SIZE_IN_PAGES + page as u64) * WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    /// Takes a free physical page, other than the `reserved` ones, growing the
+    /// underlying memory if there are none.
+    ///
+    /// Returns the page along with whether it has been used before, and so
+    /// might not be zeroed.
+    fn allocate_page(&mut self, reserved: usize) -> Result<(u32, bool), StableMemoryError> {
+        if self.free_pages.len() > reserved {
+            let page = self.free_pages.pop().expect("No free pages");
+            return Ok((page, true));
+        }
+        let size = self.memory.size();
+        let page = u32::try_from(size - HEADER_SIZE_IN_PAGES)
+            .map_err(|_| StableMemoryError::OutOfMemory)?;
+        self.memory.grow(1)?;
+        Ok((page, false))
+    }
+
+    fn set_live_page(&mut self, index: usize, page: u32) {
+        self.live_pages[index] = page;
+        self.memory.write(
+            PAGE_TABLE_OFFSET + index as u64 * PAGE_TABLE_ENTRY_SIZE_IN_BYTES,
+            &page.to_le_bytes(),
+        );
+    }
+
+    fn grow(&mut self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        let previous_size = self.live_pages.len() as u64;
+        let new_size = previous_size
+            .checked_add(added_pages)
+            .filter(|new_size| *new_size <= MAX_NUM_PAGES)
+            .ok_or(StableMemoryError::OutOfMemory)?;
+        // The new pages are not shared, so this stays the same as they are added.
+        let reserved = self.num_reserved_pages();
+        let mut allocated = vec![];
+        for _ in previous_size..new_size {
+            match self.allocate_page(reserved) {
+                Ok(page) => allocated.push(page),
+                Err(error) => {
+                    self.free_pages
+                        .extend(allocated.into_iter().map(|(page, _)| page));
+                    return Err(error);
+                }
+            }
+        }
+        for (page, used) in allocated {
+            if used {
+                self.memory.write(
+                    Self::physical_offset(page),
+                    &[0; WASM_PAGE_SIZE_IN_BYTES as usize],
+                );
+            }
+            self.live_pages.push(page);
+            self.set_live_page(self.live_pages.len() - 1, page);
+        }
+        self.memory.write(SIZE_OFFSET, &new_size.to_le_bytes());
+        Ok(previous_size)
+    }
+
+    /// Moves a live page that is shared with a snapshot to a copy of its own,
+    /// on one of the pages reserved for it.
+    fn copy_on_write(&mut self, index: usize) {
+        let page = self.live_pages[index];
+        if !self.shared_pages.contains_key(&page) {
+            return;
+        }
+        let copy = self
+            .free_pages
+            .pop()
+            .expect("No page reserved for a copy-on-write page");
+        let mut bytes = vec![0; WASM_PAGE_SIZE_IN_BYTES as usize];
+        self.memory.read(Self::physical_offset(page), &mut bytes);
+        self.memory.write(Self::physical_offset(copy), &bytes);
+        self.set_live_page(index, copy);
+    }
+
+    /// Maps a range of a memory made up of `pages` onto the underlying memory,
+    /// splitting it wherever it crosses a page boundary.
+    ///
+    /// Returns the index of each page, along with the offset within it and the
+    /// part of the range that it covers.
+    fn segments(pages: &[u32], offset: u64, len: usize) -> Vec<(usize, u64, Range<usize>)> {
+        let size_in_bytes = pages.len() as u64 * WASM_PAGE_SIZE_IN_BYTES;
+        assert!(
+            matches!(offset.checked_add(len as u64), Some(end) if end <= size_in_bytes),
+            "Snapshot memory access out of bounds"
+        );
+
+        let mut segments = vec![];
+        let mut start = 0;
+        while start < len {
+            let position = offset + start as u64;
+            let index = (position / WASM_PAGE_SIZE_IN_BYTES) as usize;
+            let offset_in_page = position % WASM_PAGE_SIZE_IN_BYTES;
+            let segment_len =
+                ((len - start) as u64).min(WASM_PAGE_SIZE_IN_BYTES - offset_in_page) as usize;
+            segments.push((index, offset_in_page, start..start + segment_len));
+            start += segment_len;
+        }
+        segments
+    }
+
+    fn read(&self, pages: &[u32], offset: u64, buf: &mut [u8]) {
+        for (index, offset_in_page, range) in Self::segments(pages, offset, buf.len()) {
+            let physical_offset = Self::physical_offset(pages[index]) + offset_in_page;
+            self.memory.read(physical_offset, &mut buf[range]);
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        for (index, offset_in_page, range) in Self::segments(&self.live_pages, offset, buf.len()) {
+            self.copy_on_write(index);
+            let physical_offset = Self::physical_offset(self.live_pages[index]) + offset_in_page;
+            self.memory.write(physical_offset, &buf[range]);
+        }
+    }
+}
+
+impl<M: Memory> Snapshot<M> {
+    /// Gets the id of the snapshot.
+    pub fn id(&self) -> SnapshotId {
+        self.id
+    }
+}
+
+impl<M: Memory> Clone for SnapshotMemory<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> Clone for Snapshot<M> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Memory> Memory for SnapshotMemory<M> {
+    fn size(&self) -> u64 {
+        self.inner.borrow().live_pages.len() as u64
+    }
+
+    fn grow(&self, added_pages: u64) -> Result<u64, StableMemoryError> {
+        self.inner.borrow_mut().grow(added_pages)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being read is beyond the size of the memory.
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let inner = self.inner.borrow();
+        inner.read(&inner.live_pages, offset, buf)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being written is beyond the size of the memory.
+    fn write(&self, offset: u64, buf: &[u8]) {
+        self.inner.borrow_mut().write(offset, buf)
+    }
+
+    fn flush(&self) {
+        self.inner.borrow().memory.flush()
+    }
+}
+
+impl<M: Memory> Memory for Snapshot<M> {
+    fn size(&self) -> u64 {
+        let inner = self.inner.borrow();
+        inner
+            .snapshots
+            .get(&self.id)
+            .expect("Snapshot has been released")
+            .len() as u64
+    }
+
+    /// Snapshots cannot grow.
+    fn grow(&self, _added_pages: u64) -> Result<u64, StableMemoryError> {
+        Err(StableMemoryError::OutOfMemory)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range being read is beyond the size of the snapshot.
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let inner = self.inner.borrow();
+        let pages = inner
+            .snapshots
+            .get(&self.id)
+            .expect("Snapshot has been released");
+        inner.read(pages, offset, buf)
+    }
+
+    /// # Panics
+    ///
+    /// Always panics, as snapshots are read-only.
+    fn write(&self, _offset: u64, _buf: &[u8]) {
+        panic!("Snapshots are read-only")
+    }
+}
@@ -0,0 +1,596 @@
+// An ordered map that lives in a memory rather than on the heap.
+//
+// The map is a B-tree whose nodes are read from and written to the memory as
+// they are visited, so only the nodes on the path being followed are ever on
+// the heap. Keys and values are bounded in size, so every node takes up the
+// same number of bytes, and the slots of nodes that have been freed are kept
+// in a list to be reused. Nodes are split on the way down when inserting and
+// topped up on the way down when removing, so neither ever has to walk back
+// up the tree.
+//
+// Layout:
+// -------------------------------------------------- <- Byte 0
+// Magic "ICB"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Reserved space                         ↕ 4 bytes
+// Maximum size of a key (in bytes)       ↕ 4 bytes
+// Maximum size of a value (in bytes)     ↕ 4 bytes
+// Address of the root node               ↕ 8 bytes
+// Number of entries                      ↕ 8 bytes
+// Address of the first free node         ↕ 8 bytes
+// End of the nodes (in bytes)            ↕ 8 bytes
+// -------------------------------------------------- <- Byte 48
+// Node 0                                 ↕ Node size
+// Node 1                                 ↕ Node size
+// ...
+//
+// Node layout:
+// -------------------------------------------------- <- Address of the node
+// Leaf (1) or internal (0)               ↕ 1 byte
+// Number of entries                      ↕ 1 byte
+// Reserved space                         ↕ 6 bytes
+// Length of key 0 (in bytes)             ↕ 4 bytes
+// Key 0                                  ↕ Maximum size of a key
+// Length of value 0 (in bytes)           ↕ 4 bytes
+// Value 0                                ↕ Maximum size of a value
+// ...
+// Value 10                               ↕ Maximum size of a value
+// Address of child 0                     ↕ 8 bytes
+// ...
+// Address of child 11                    ↕ 8 bytes
+//
+// A free node instead holds the address of the next free node in its first
+// 8 bytes.
+use crate::error::Error;
+use crate::internal::ensure_capacity;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::storable::Storable;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 3] = b"ICB";
+const LAYOUT_VERSION: u8 = 1;
+
+const HEADER_SIZE_IN_BYTES: u64 = 48;
+const ROOT_OFFSET: u64 = 16;
+const LEN_OFFSET: u64 = 24;
+const FREE_NODES_OFFSET: u64 = 32;
+const NODES_END_OFFSET: u64 = 40;
+
+// The minimum degree of the tree: every node but the root holds at least
+// `B - 1` entries, and no node holds more than `2 * B - 1`.
+const B: usize = 6;
+const CAPACITY: usize = 2 * B - 1;
+
+const NODE_HEADER_SIZE_IN_BYTES: u64 = 8;
+const LEN_SIZE_IN_BYTES: u64 = 4;
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 0;
+// Marks an empty tree, and the end of the list of free nodes.
+const NULL: u64 = 0;
+
+/// A map ordered by key that lives in a memory, such as a `VirtualMemory`
+/// handed out by a `MemoryManager`.
+///
+/// Keys and values must encode to at most `K::MAX_SIZE` and `V::MAX_SIZE`
+/// bytes.
+pub struct StableBTreeMap<K: Storable + Ord, V: Storable, M: Memory = Ic0StableMemory> {
+    memory: M,
+    root: u64,
+    len: u64,
+    free_nodes: u64,
+    nodes_end: u64,
+    _marker: PhantomData<(K, V)>,
+}
+
+/// A node of the tree, as loaded onto the heap.
+struct Node<K, V> {
+    address: u64,
+    is_leaf: bool,
+    keys: Vec<K>,
+    values: Vec<V>,
+    children: Vec<u64>,
+}
+
+fn read_u64<M: Memory>(memory: &M, offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    memory.read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u64<M: Memory>(memory: &M, offset: u64, value: u64) {
+    memory.write(offset, &value.to_le_bytes())
+}
+
+impl<K: Storable + Ord, V: Storable, M: Memory> StableBTreeMap<K, V, M> {
+    /// Loads the map stored in `memory`, or creates a new empty one if
+    /// `memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` is not empty and does not hold a map with keys and
+    /// values of the maximum sizes of `K` and `V`.
+    pub fn init(memory: M) -> Result<Self, Error> {
+        if memory.size() == 0 {
+            ensure_capacity(&memory, HEADER_SIZE_IN_BYTES)?;
+            let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+            header[0..3].copy_from_slice(MAGIC);
+            header[3] = LAYOUT_VERSION;
+            header[8..12].copy_from_slice(&K::MAX_SIZE.to_le_bytes());
+            header[12..16].copy_from_slice(&V::MAX_SIZE.to_le_bytes());
+            header[40..48].copy_from_slice(&HEADER_SIZE_IN_BYTES.to_le_bytes());
+            memory.write(0, &header);
+            return Ok(Self {
+                memory,
+                root: NULL,
+                len: 0,
+                free_nodes: NULL,
+                nodes_end: HEADER_SIZE_IN_BYTES,
+                _marker: PhantomData,
+            });
+        }
+
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        memory.read(0, &mut header);
+        assert_eq!(&header[0..3], MAGIC, "Memory does not hold a map");
+        assert_eq!(header[3], LAYOUT_VERSION, "Unsupported map layout version");
+        assert_eq!(
+            u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            K::MAX_SIZE,
+            "Maximum key size does not match"
+        );
+        assert_eq!(
+            u32::from_le_bytes(header[12..16].try_into().unwrap()),
+            V::MAX_SIZE,
+            "Maximum value size does not match"
+        );
+        let field = |offset: u64| {
+            let offset = offset as usize;
+            u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap())
+        };
+        Ok(Self {
+            root: field(ROOT_OFFSET),
+            len: field(LEN_OFFSET),
+            free_nodes: field(FREE_NODES_OFFSET),
+            nodes_end: field(NODES_END_OFFSET),
+            memory,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Gets the number of entries in the map.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the value of the given key, if there is one.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut address = self.root;
+        while address != NULL {
+            let mut node = self.load(address);
+            match node.keys.binary_search(key) {
+                Ok(index) => return Some(node.values.swap_remove(index)),
+                Err(_) if node.is_leaf => return None,
+                Err(index) => address = node.children[index],
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if the map has an entry for the given key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts an entry, returning the value it replaces if the key was
+    /// already in the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key or the value encodes to more than its maximum size.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Error> {
+        assert!(key.to_bytes().len() <= K::MAX_SIZE as usize, "Key is larger than its maximum size");
+        assert!(value.to_bytes().len() <= V::MAX_SIZE as usize, "Value is larger than its maximum size");
+
+        let mut node = if self.root == NULL {
+            let root = self.allocate_node(true)?;
+            self.set_root(root.address);
+            root
+        } else {
+            let root = self.load(self.root);
+            if root.keys.len() < CAPACITY {
+                root
+            } else {
+                let mut new_root = self.allocate_node(false)?;
+                new_root.children.push(root.address);
+                self.split_child(&mut new_root, 0, root)?;
+                self.set_root(new_root.address);
+                new_root
+            }
+        };
+
+        loop {
+            let index = match node.keys.binary_search(&key) {
+                Ok(index) => {
+                    let old_value = std::mem::replace(&mut node.values[index], value);
+                    self.save(&node);
+                    return Ok(Some(old_value));
+                }
+                Err(index) => index,
+            };
+            if node.is_leaf {
+                node.keys.insert(index, key);
+                node.values.insert(index, value);
+                self.save(&node);
+                self.set_len(self.len + 1);
+                return Ok(None);
+            }
+
+            let child = self.load(node.children[index]);
+            if child.keys.len() < CAPACITY {
+                node = child;
+                continue;
+            }
+            self.split_child(&mut node, index, child)?;
+            // The middle key of the child has moved up into `node`, so look
+            // at `node` again to tell which half of the child to go into.
+        }
+    }
+
+    /// Removes the entry for the given key, returning its value if there was
+    /// one.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if self.root == NULL {
+            return None;
+        }
+        let root = self.load(self.root);
+        let value = self.remove_from(root, key);
+
+        let root = self.load(self.root);
+        if root.keys.is_empty() {
+            let new_root = if root.is_leaf { NULL } else { root.children[0] };
+            self.free_node(root.address);
+            self.set_root(new_root);
+        }
+        if value.is_some() {
+            self.set_len(self.len - 1);
+        }
+        value
+    }
+
+    /// Iterates over the entries in order of their keys, reading each node as
+    /// it is reached.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        let mut iter = Iter { map: self, stack: vec![] };
+        iter.push_leftmost(self.root);
+        iter
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Removes `key` from the subtree under `node`, which holds at least `B`
+    /// entries unless it is the root.
+    fn remove_from(&mut self, mut node: Node<K, V>, key: &K) -> Option<V> {
+        loop {
+            match node.keys.binary_search(key) {
+                Ok(index) if node.is_leaf => {
+                    node.keys.remove(index);
+                    let value = node.values.remove(index);
+                    self.save(&node);
+                    return Some(value);
+                }
+                Ok(index) => {
+                    // Replace the entry with the one before or after it, taken
+                    // from a child that can spare one, or merge the children
+                    // either side of it and remove it from there.
+                    let left = self.load(node.children[index]);
+                    if left.keys.len() >= B {
+                        let (last_key, last_value) = self.remove_last(left);
+                        node.keys[index] = last_key;
+                        let value = std::mem::replace(&mut node.values[index], last_value);
+                        self.save(&node);
+                        return Some(value);
+                    }
+                    let right = self.load(node.children[index + 1]);
+                    if right.keys.len() >= B {
+                        let (first_key, first_value) = self.remove_first(right);
+                        node.keys[index] = first_key;
+                        let value = std::mem::replace(&mut node.values[index], first_value);
+                        self.save(&node);
+                        return Some(value);
+                    }
+                    node = self.merge_children(&mut node, index, left, right);
+                }
+                Err(_) if node.is_leaf => return None,
+                Err(index) => node = self.top_up_child(&mut node, index),
+            }
+        }
+    }
+
+    /// Removes the last entry of the subtree under `node`, which holds at
+    /// least `B` entries.
+    fn remove_last(&mut self, mut node: Node<K, V>) -> (K, V) {
+        while !node.is_leaf {
+            let last = node.children.len() - 1;
+            node = self.top_up_child(&mut node, last);
+        }
+        let key = node.keys.pop().unwrap();
+        let value = node.values.pop().unwrap();
+        self.save(&node);
+        (key, value)
+    }
+
+    /// Removes the first entry of the subtree under `node`, which holds at
+    /// least `B` entries.
+    fn remove_first(&mut self, mut node: Node<K, V>) -> (K, V) {
+        while !node.is_leaf {
+            node = self.top_up_child(&mut node, 0);
+        }
+        let key = node.keys.remove(0);
+        let value = node.values.remove(0);
+        self.save(&node);
+        (key, value)
+    }
+
+    /// Makes sure that the child of `node` at `index` holds at least `B`
+    /// entries, by moving one over from a sibling or by merging it with one,
+    /// and returns it.
+    fn top_up_child(&mut self, node: &mut Node<K, V>, index: usize) -> Node<K, V> {
+        let mut child = self.load(node.children[index]);
+        if child.keys.len() >= B {
+            return child;
+        }
+
+        if index > 0 {
+            let mut left = self.load(node.children[index - 1]);
+            if left.keys.len() >= B {
+                let key = std::mem::replace(&mut node.keys[index - 1], left.keys.pop().unwrap());
+                let value = std::mem::replace(&mut node.values[index - 1], left.values.pop().unwrap());
+                child.keys.insert(0, key);
+                child.values.insert(0, value);
+                if !child.is_leaf {
+                    child.children.insert(0, left.children.pop().unwrap());
+                }
+                self.save(&left);
+                self.save(&child);
+                self.save(node);
+                return child;
+            }
+            if index == node.children.len() - 1 {
+                return self.merge_children(node, index - 1, left, child);
+            }
+        }
+
+        let mut right = self.load(node.children[index + 1]);
+        if right.keys.len() >= B {
+            let key = std::mem::replace(&mut node.keys[index], right.keys.remove(0));
+            let value = std::mem::replace(&mut node.values[index], right.values.remove(0));
+            child.keys.push(key);
+            child.values.push(value);
+            if !child.is_leaf {
+                child.children.push(right.children.remove(0));
+            }
+            self.save(&right);
+            self.save(&child);
+            self.save(node);
+            return child;
+        }
+        self.merge_children(node, index, child, right)
+    }
+
+    /// Merges the children of `node` either side of the entry at `index`,
+    /// along with that entry, into the left child and returns it.
+    fn merge_children(
+        &mut self,
+        node: &mut Node<K, V>,
+        index: usize,
+        mut left: Node<K, V>,
+        right: Node<K, V>,
+    ) -> Node<K, V> {
+        left.keys.push(node.keys.remove(index));
+        left.values.push(node.values.remove(index));
+        node.children.remove(index + 1);
+        left.keys.extend(right.keys);
+        left.values.extend(right.values);
+        left.children.extend(right.children);
+        self.save(&left);
+        self.save(node);
+        self.free_node(right.address);
+        left
+    }
+
+    /// Splits the full child of `node` at `index` in two, moving its middle
+    /// entry up into `node`.
+    fn split_child(&mut self, node: &mut Node<K, V>, index: usize, mut child: Node<K, V>) -> Result<(), Error> {
+        let mut right = self.allocate_node(child.is_leaf)?;
+        right.keys = child.keys.split_off(B);
+        right.values = child.values.split_off(B);
+        if !child.is_leaf {
+            right.children = child.children.split_off(B);
+        }
+        node.keys.insert(index, child.keys.pop().unwrap());
+        node.values.insert(index, child.values.pop().unwrap());
+        node.children.insert(index + 1, right.address);
+        self.save(&child);
+        self.save(&right);
+        self.save(node);
+        Ok(())
+    }
+
+    fn entry_size() -> u64 {
+        2 * LEN_SIZE_IN_BYTES + K::MAX_SIZE as u64 + V::MAX_SIZE as u64
+    }
+
+    fn node_size() -> u64 {
+        NODE_HEADER_SIZE_IN_BYTES + CAPACITY as u64 * Self::entry_size() + (CAPACITY as u64 + 1) * 8
+    }
+
+    /// Takes a node from the list of free nodes, or from the end of the
+    /// nodes if there are none, growing the memory if needed.
+    fn allocate_node(&mut self, is_leaf: bool) -> Result<Node<K, V>, Error> {
+        let address = if self.free_nodes != NULL {
+            let address = self.free_nodes;
+            self.free_nodes = read_u64(&self.memory, address);
+            write_u64(&self.memory, FREE_NODES_OFFSET, self.free_nodes);
+            address
+        } else {
+            let address = self.nodes_end;
+            let nodes_end = address
+                .checked_add(Self::node_size())
+                .ok_or(Error::OffsetOverflow)?;
+            ensure_capacity(&self.memory, nodes_end)?;
+            self.nodes_end = nodes_end;
+            write_u64(&self.memory, NODES_END_OFFSET, nodes_end);
+            address
+        };
+        Ok(Node {
+            address,
+            is_leaf,
+            keys: vec![],
+            values: vec![],
+            children: vec![],
+        })
+    }
+
+    fn free_node(&mut self, address: u64) {
+        write_u64(&self.memory, address, self.free_nodes);
+        self.free_nodes = address;
+        write_u64(&self.memory, FREE_NODES_OFFSET, address);
+    }
+
+    fn load(&self, address: u64) -> Node<K, V> {
+        let mut bytes = vec![0; Self::node_size() as usize];
+        self.memory.read(address, &mut bytes);
+        let is_leaf = bytes[0] == LEAF;
+        let len = bytes[1] as usize;
+
+        let read_len = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + LEN_SIZE_IN_BYTES as usize].try_into().unwrap()) as usize
+        };
+        let mut keys = Vec::with_capacity(CAPACITY);
+        let mut values = Vec::with_capacity(CAPACITY);
+        let mut offset = NODE_HEADER_SIZE_IN_BYTES as usize;
+        for _ in 0..len {
+            let key_offset = offset + LEN_SIZE_IN_BYTES as usize;
+            keys.push(K::from_bytes(&bytes[key_offset..key_offset + read_len(offset)]));
+            offset = key_offset + K::MAX_SIZE as usize;
+            let value_offset = offset + LEN_SIZE_IN_BYTES as usize;
+            values.push(V::from_bytes(&bytes[value_offset..value_offset + read_len(offset)]));
+            offset = value_offset + V::MAX_SIZE as usize;
+        }
+
+        let mut children = Vec::with_capacity(CAPACITY + 1);
+        if !is_leaf {
+            let children_offset = (NODE_HEADER_SIZE_IN_BYTES + CAPACITY as u64 * Self::entry_size()) as usize;
+            for child in bytes[children_offset..].chunks_exact(8).take(len + 1) {
+                children.push(u64::from_le_bytes(child.try_into().unwrap()));
+            }
+        }
+
+        Node {
+            address,
+            is_leaf,
+            keys,
+            values,
+            children,
+        }
+    }
+
+    fn save(&self, node: &Node<K, V>) {
+        let mut bytes = vec![0; Self::node_size() as usize];
+        bytes[0] = if node.is_leaf { LEAF } else { INTERNAL };
+        bytes[1] = node.keys.len() as u8;
+
+        let mut offset = NODE_HEADER_SIZE_IN_BYTES as usize;
+        let mut write = |encoded: Vec<u8>, max_size: u32| {
+            bytes[offset..offset + LEN_SIZE_IN_BYTES as usize]
+                .copy_from_slice(&(encoded.len() as u32).to_le_bytes());
+            offset += LEN_SIZE_IN_BYTES as usize;
+            bytes[offset..offset + encoded.len()].copy_from_slice(&encoded);
+            offset += max_size as usize;
+        };
+        for (key, value) in node.keys.iter().zip(&node.values) {
+            write(key.to_bytes(), K::MAX_SIZE);
+            write(value.to_bytes(), V::MAX_SIZE);
+        }
+
+        let children_offset = (NODE_HEADER_SIZE_IN_BYTES + CAPACITY as u64 * Self::entry_size()) as usize;
+        for (index, child) in node.children.iter().enumerate() {
+            let child_offset = children_offset + index * 8;
+            bytes[child_offset..child_offset + 8].copy_from_slice(&child.to_le_bytes());
+        }
+        self.memory.write(node.address, &bytes);
+    }
+
+    fn set_root(&mut self, root: u64) {
+        self.root = root;
+        write_u64(&self.memory, ROOT_OFFSET, root);
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.len = len;
+        write_u64(&self.memory, LEN_OFFSET, len);
+    }
+}
+
+/// A node on the path of an `Iter`.
+struct Frame<K, V> {
+    /// The entries of the node yet to be visited.
+    entries: std::vec::IntoIter<(K, V)>,
+    children: Vec<u64>,
+    /// The index of the child that was last descended into.
+    child_index: usize,
+}
+
+/// Walks the tree in order, keeping the nodes on the path to the next entry
+/// on the heap.
+struct Iter<'a, K: Storable + Ord, V: Storable, M: Memory> {
+    map: &'a StableBTreeMap<K, V, M>,
+    stack: Vec<Frame<K, V>>,
+}
+
+impl<'a, K: Storable + Ord, V: Storable, M: Memory> Iter<'a, K, V, M> {
+    /// Descends from the node at `address` to its leftmost leaf.
+    fn push_leftmost(&mut self, mut address: u64) {
+        while address != NULL {
+            let node = self.map.load(address);
+            address = node.children.first().copied().unwrap_or(NULL);
+            let entries: Vec<_> = node.keys.into_iter().zip(node.values).collect();
+            self.stack.push(Frame {
+                entries: entries.into_iter(),
+                children: node.children,
+                child_index: 0,
+            });
+        }
+    }
+}
+
+impl<'a, K: Storable + Ord, V: Storable, M: Memory> Iterator for Iter<'a, K, V, M> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            match frame.entries.next() {
+                Some(entry) => {
+                    frame.child_index += 1;
+                    if let Some(&child) = frame.children.get(frame.child_index) {
+                        self.push_leftmost(child);
+                    }
+                    return Some(entry);
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
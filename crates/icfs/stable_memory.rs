@@ -1,183 +1,635 @@
 // Based on https://github.com/dfinity/cdk-rs/blob/a253119adb08929b6304d007ee0a6a37960656ed/src/ic-cdk/src/api/stable.rs
 // * Supports 64-bit addressed memory
-use ic_cdk::api::stable::{
-    stable64_read, stable64_write, StableMemoryError,
-};
+// * Supports any implementation of `Memory`
+use crate::chunks::Chunks;
+use crate::dirty_pages::DirtyPages;
+use crate::error::Error;
+use crate::growth_policy::GrowthPolicy;
+use crate::internal::{div_ceil, offset_by};
+use crate::memory::{Ic0StableMemory, Memory, WASM_PAGE_SIZE_IN_BYTES};
+use crate::positioned::{ReadAt, WriteAt};
+use std::convert::TryFrom;
 use std::io;
 
-const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024; // 64KB
+/// The number of bytes at the start of a memory used to persist its logical
+/// length, when logical length tracking is enabled.
+const LEN_SIZE_IN_BYTES: u64 = 8;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct StableMemory {
-    offset: usize,
+pub struct StableMemory<M: Memory = Ic0StableMemory> {
+    memory: M,
+    offset: u64,
+    growth_policy: GrowthPolicy,
+    max_pages: Option<u64>,
+    logical_len: bool,
+    dirty_pages: Option<DirtyPages>,
 }
 
-fn get_offset(stable_memory: &StableMemory) -> usize {
+fn get_offset<M: Memory>(stable_memory: &StableMemory<M>) -> u64 {
     stable_memory.offset
 }
 
-fn set_offset(stable_memory: &mut StableMemory, offset: usize) {
+fn set_offset<M: Memory>(stable_memory: &mut StableMemory<M>, offset: u64) {
     stable_memory.offset = offset
 }
 
-/// Returns a copy of the stable memory.
+/// Returns a copy of the memory.
 ///
 /// This will map the whole memory (even if not all of it has been written to).
-pub fn bytes() -> Vec<u8> {
-    let capacity = capacity();
-    let mut vec = Vec::with_capacity(capacity);
-    unsafe {
-        vec.set_len(capacity);
-    }
-    stable64_read(0, vec.as_mut_slice());
+///
+/// # Panics
+///
+/// Panics if the memory is too large to copy into the address space of the
+/// caller.
+pub fn bytes<M: Memory>(memory: &M) -> Vec<u8> {
+    let capacity = usize::try_from(capacity(memory)).expect("Memory does not fit in a Vec");
+    let mut vec = vec![0; capacity];
+    memory.read(0, vec.as_mut_slice());
     vec
 }
 
-/// Gets capacity of the stable memory in bytes.
-pub fn capacity() -> usize {
-    (size() as usize) << 16
+/// Returns a copy of `len` bytes of the data, starting at `start`.
+pub fn read_range<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    start: u64,
+    len: u64,
+) -> Result<Vec<u8>, Error> {
+    let end = start.checked_add(len).ok_or(Error::OffsetOverflow)?;
+    if end > data_end(stable_memory) {
+        return Err(Error::OutOfBounds);
+    }
+    let mut vec = vec![0; usize::try_from(len).map_err(|_| Error::OffsetOverflow)?];
+    let memory_offset = memory_offset(stable_memory, start)?;
+    stable_memory.memory.read(memory_offset, vec.as_mut_slice());
+    Ok(vec)
+}
+
+/// Gets capacity of the memory in bytes.
+pub fn capacity<M: Memory>(memory: &M) -> u64 {
+    size(memory).saturating_mul(WASM_PAGE_SIZE_IN_BYTES)
 }
 
 /// Attempts to grow the memory by adding new pages.
-pub fn grow(added_pages: u64) -> Result<u64, StableMemoryError> {
-    ic_cdk::api::stable::stable64_grow(added_pages)
+pub fn grow<M: Memory>(memory: &M, added_pages: u64) -> Result<u64, Error> {
+    let available = size(memory);
+    memory.grow(added_pages).map_err(|_| Error::GrowFailed {
+        requested: added_pages,
+        available,
+    })
 }
 
-/// Gets current size of the stable memory in WebAssembly pages.
-pub fn size() -> u64 {
-    ic_cdk::api::stable::stable64_size()
+/// Gets current size of the memory in WebAssembly pages.
+pub fn size<M: Memory>(memory: &M) -> u64 {
+    memory.size()
 }
 
-/// Reads data from the stable memory location specified by an offset.
-pub fn read(stable_memory: &mut StableMemory, buf: &mut [u8]) -> Result<usize, StableMemoryError> {
-    let offset = get_offset(stable_memory);
-    let capacity = capacity();
-    let read_buf = if buf.len() + offset > capacity {
-        if offset <= capacity {
-            &mut buf[..capacity - offset]
+/// Gets the number of pages needed to span `len` bytes.
+fn pages_for(len: u64) -> u64 {
+    div_ceil(len, WASM_PAGE_SIZE_IN_BYTES)
+}
+
+/// Gets the logical length persisted at the start of the memory.
+fn get_logical_len<M: Memory>(memory: &M) -> u64 {
+    if capacity(memory) < LEN_SIZE_IN_BYTES {
+        return 0;
+    }
+    let mut bytes = [0; LEN_SIZE_IN_BYTES as usize];
+    memory.read(0, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn set_logical_len<M: Memory>(stable_memory: &StableMemory<M>, len: u64) {
+    write_memory(stable_memory, 0, &len.to_le_bytes())
+}
+
+/// Writes data to the memory location specified by an offset, marking the
+/// pages written as dirty if they are being tracked.
+fn write_memory<M: Memory>(stable_memory: &StableMemory<M>, memory_offset: u64, buf: &[u8]) {
+    stable_memory.memory.write(memory_offset, buf);
+    if let Some(dirty_pages) = &stable_memory.dirty_pages {
+        dirty_pages.mark(memory_offset, buf.len() as u64);
+    }
+}
+
+/// Gets the offset in the memory at which the data of the handle starts.
+fn data_start<M: Memory>(stable_memory: &StableMemory<M>) -> u64 {
+    if stable_memory.logical_len {
+        LEN_SIZE_IN_BYTES
+    } else {
+        0
+    }
+}
+
+/// Gets the offset (relative to the start of the data) at which the data of
+/// the handle ends.
+///
+/// This is the logical length when it is being tracked, and the capacity of
+/// the memory otherwise.
+fn data_end<M: Memory>(stable_memory: &StableMemory<M>) -> u64 {
+    if stable_memory.logical_len {
+        get_logical_len(&stable_memory.memory)
+    } else {
+        capacity(&stable_memory.memory)
+    }
+}
+
+/// Maps an offset relative to the start of the data to an offset in the memory.
+pub(crate) fn memory_offset<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+) -> Result<u64, Error> {
+    data_start(stable_memory)
+        .checked_add(offset)
+        .ok_or(Error::OffsetOverflow)
+}
+
+/// Zeroes the data from `start` up to `end`.
+fn zero<M: Memory>(stable_memory: &StableMemory<M>, start: u64, end: u64) -> Result<(), Error> {
+    let zeros = [0; 4096];
+    let mut offset = start;
+    while offset < end {
+        let len = (end - offset).min(zeros.len() as u64) as usize;
+        write_memory(
+            stable_memory,
+            memory_offset(stable_memory, offset)?,
+            &zeros[..len],
+        );
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+/// Gets the total length of a list of buffers.
+fn total_len<B: std::ops::Deref<Target = [u8]>>(bufs: &[B]) -> Result<u64, Error> {
+    bufs.iter()
+        .try_fold(0u64, |total, buf| total.checked_add(buf.len() as u64))
+        .ok_or(Error::OffsetOverflow)
+}
+
+/// Gets how many of `len` bytes can be read starting at an offset.
+///
+/// When the logical length is being tracked, reading past it is not an error
+/// and reads nothing, as with a file.
+fn readable_len<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    len: u64,
+) -> Result<u64, Error> {
+    let data_end = data_end(stable_memory);
+    let end = offset.checked_add(len).ok_or(Error::OffsetOverflow)?;
+    if end > data_end {
+        if offset <= data_end {
+            Ok(data_end - offset)
+        } else if stable_memory.logical_len {
+            Ok(0)
         } else {
-            return Err(StableMemoryError::OutOfBounds);
+            Err(Error::OutOfBounds)
         }
     } else {
-        buf
-    };
-    stable64_read(offset as u64, read_buf);
-    set_offset(stable_memory, offset + read_buf.len());
-    Ok(read_buf.len())
+        Ok(len)
+    }
 }
 
-fn seek(stable_memory: &mut StableMemory, pos: io::SeekFrom) -> Result<u64, StableMemoryError> {
-    match pos {
-        io::SeekFrom::Start(start) => {
-            set_offset(stable_memory, start as usize);
-            Ok(get_offset(stable_memory) as u64)
+/// Reads data from the memory location specified by an offset.
+///
+/// When the logical length is being tracked, reading past it is not an error
+/// and reads nothing, as with a file.
+pub fn read_at<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    // Never more than `buf.len()`, so it fits in a `usize`.
+    let read_len = readable_len(stable_memory, offset, buf.len() as u64)? as usize;
+    let memory_offset = memory_offset(stable_memory, offset)?;
+    stable_memory
+        .memory
+        .read(memory_offset, &mut buf[..read_len]);
+    Ok(read_len)
+}
+
+/// Reads data from the memory location specified by an offset into each
+/// buffer in turn, filling one before moving on to the next.
+pub fn read_vectored_at<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    bufs: &mut [io::IoSliceMut<'_>],
+) -> Result<usize, Error> {
+    // Never more than the total length of `bufs`, so it fits in a `usize`.
+    let read_len = readable_len(stable_memory, offset, total_len(bufs)?)? as usize;
+    let mut memory_offset = memory_offset(stable_memory, offset)?;
+    let mut remaining = read_len;
+    for buf in bufs.iter_mut() {
+        if remaining == 0 {
+            break;
         }
-        io::SeekFrom::End(end) => {
-            let new_offset = capacity() as i64 + end;
-            if new_offset >= 0 {
-                set_offset(stable_memory, new_offset as usize);
-                Ok(get_offset(stable_memory) as u64)
+        let len = buf.len().min(remaining);
+        stable_memory.memory.read(memory_offset, &mut buf[..len]);
+        memory_offset += len as u64;
+        remaining -= len;
+    }
+    Ok(read_len)
+}
+
+/// Reads data from the memory location specified by the cursor.
+pub fn read<M: Memory>(
+    stable_memory: &mut StableMemory<M>,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let offset = get_offset(stable_memory);
+    let read_len = read_at(stable_memory, offset, buf)?;
+    set_offset(stable_memory, offset + read_len as u64);
+    Ok(read_len)
+}
+
+/// Reads data from the memory location specified by the cursor into each
+/// buffer in turn.
+pub fn read_vectored<M: Memory>(
+    stable_memory: &mut StableMemory<M>,
+    bufs: &mut [io::IoSliceMut<'_>],
+) -> Result<usize, Error> {
+    let offset = get_offset(stable_memory);
+    let read_len = read_vectored_at(stable_memory, offset, bufs)?;
+    set_offset(stable_memory, offset + read_len as u64);
+    Ok(read_len)
+}
+
+fn seek<M: Memory>(stable_memory: &mut StableMemory<M>, pos: io::SeekFrom) -> Result<u64, Error> {
+    let new_offset = match pos {
+        io::SeekFrom::Start(start) => start,
+        io::SeekFrom::End(end) => offset_by(data_end(stable_memory), end)?,
+        io::SeekFrom::Current(current) => offset_by(get_offset(stable_memory), current)?,
+    };
+    set_offset(stable_memory, new_offset);
+    Ok(new_offset)
+}
+
+/// Grows the memory so that it spans at least `pages_required` pages.
+///
+/// The memory is grown according to the growth policy of the handle, but
+/// never beyond its maximum number of pages. If growing by the amount the
+/// policy asks for fails, growing by only what is required is attempted.
+fn ensure_size<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    pages_required: u64,
+) -> Result<(), Error> {
+    let memory = &stable_memory.memory;
+    let size = size(memory);
+    let max_additional_pages = stable_memory
+        .max_pages
+        .map_or(u64::MAX, |max_pages| max_pages.saturating_sub(size));
+    let additional_pages_required = pages_required
+        .saturating_sub(size)
+        .min(max_additional_pages);
+    let additional_pages = stable_memory
+        .growth_policy
+        .additional_pages(size, pages_required)
+        .min(max_additional_pages);
+    if additional_pages == 0 {
+        return Ok(());
+    }
+    grow(memory, additional_pages)
+        .or_else(|error| {
+            if additional_pages > additional_pages_required {
+                grow(memory, additional_pages_required)
             } else {
-                Err(StableMemoryError::OutOfBounds)
+                Err(error)
             }
+        })
+        .map(|_| ())
+}
+
+/// Grows the memory so that `len` bytes can be written starting at an offset,
+/// and gets how many of them fit.
+///
+/// Writes are cut short if the memory cannot grow past its maximum number of
+/// pages. The only condition where this will error out is if it cannot grow
+/// the memory.
+fn writable_len<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    len: u64,
+) -> Result<u64, Error> {
+    let end = offset.checked_add(len).ok_or(Error::OffsetOverflow)?;
+    ensure_size(stable_memory, pages_for(memory_offset(stable_memory, end)?))?;
+    let capacity = capacity(&stable_memory.memory).saturating_sub(data_start(stable_memory));
+    if end > capacity {
+        if offset <= capacity {
+            Ok(capacity - offset)
+        } else {
+            Err(Error::OutOfBounds)
         }
-        io::SeekFrom::Current(current) => {
-            let new_offset = get_offset(stable_memory) as i64 + current;
-            if new_offset >= 0 {
-                set_offset(stable_memory, new_offset as usize);
-                Ok(get_offset(stable_memory) as u64)
-            } else {
-                Err(StableMemoryError::OutOfBounds)
-            }
+    } else {
+        Ok(len)
+    }
+}
+
+/// Extends the logical length, if it is being tracked, to cover the bytes
+/// written up to `write_end`.
+fn extend_logical_len<M: Memory>(stable_memory: &StableMemory<M>, write_end: u64) {
+    if stable_memory.logical_len && write_end > get_logical_len(&stable_memory.memory) {
+        set_logical_len(stable_memory, write_end);
+    }
+}
+
+/// Writes a byte slice to the memory location specified by an offset.
+///
+/// Writes are cut short if the memory cannot grow past its maximum number of
+/// pages. The only condition where this will error out is if it cannot grow
+/// the memory.
+///
+/// When the logical length is being tracked, it is extended to cover the
+/// bytes written.
+pub fn write_at<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    buf: &[u8],
+) -> Result<usize, Error> {
+    // Never more than `buf.len()`, so it fits in a `usize`.
+    let write_len = writable_len(stable_memory, offset, buf.len() as u64)? as usize;
+    let memory_offset = memory_offset(stable_memory, offset)?;
+    write_memory(stable_memory, memory_offset, &buf[..write_len]);
+    if write_len > 0 {
+        extend_logical_len(stable_memory, offset + write_len as u64);
+    }
+    Ok(write_len)
+}
+
+/// Writes each byte slice in turn to the memory location specified by an
+/// offset.
+///
+/// The memory is grown at most once, for all of the slices together.
+pub fn write_vectored_at<M: Memory>(
+    stable_memory: &StableMemory<M>,
+    offset: u64,
+    bufs: &[io::IoSlice<'_>],
+) -> Result<usize, Error> {
+    // Never more than the total length of `bufs`, so it fits in a `usize`.
+    let write_len = writable_len(stable_memory, offset, total_len(bufs)?)? as usize;
+    let mut memory_offset = memory_offset(stable_memory, offset)?;
+    let mut remaining = write_len;
+    for buf in bufs {
+        if remaining == 0 {
+            break;
+        }
+        let len = buf.len().min(remaining);
+        write_memory(stable_memory, memory_offset, &buf[..len]);
+        memory_offset += len as u64;
+        remaining -= len;
+    }
+    if write_len > 0 {
+        extend_logical_len(stable_memory, offset + write_len as u64);
+    }
+    Ok(write_len)
+}
+
+/// Truncates or extends the data to `len` bytes.
+///
+/// Bytes past the logical length are kept zeroed, so that extending the data
+/// (whether by this or by writing past the end) fills the gap with zeros.
+fn set_len<M: Memory>(stable_memory: &StableMemory<M>, len: u64) -> Result<(), Error> {
+    let memory = &stable_memory.memory;
+    let logical_len = get_logical_len(memory);
+    if len > logical_len {
+        let pages_required = pages_for(memory_offset(stable_memory, len)?);
+        ensure_size(stable_memory, pages_required)?;
+        let available = size(memory);
+        if available < pages_required {
+            return Err(Error::GrowFailed {
+                requested: pages_required - available,
+                available,
+            });
         }
+    } else if len < logical_len {
+        zero(stable_memory, len, logical_len)?;
+    } else {
+        return Ok(());
     }
+    set_logical_len(stable_memory, len);
+    Ok(())
 }
 
-/// Writes a byte slice to the buffer.
+/// Writes a byte slice to the memory location specified by the cursor.
 ///
 /// The only condition where this will
 /// error out is if it cannot grow the memory.
-pub fn write(stable_memory: &mut StableMemory, buf: &[u8]) -> Result<usize, StableMemoryError> {
+pub fn write<M: Memory>(stable_memory: &mut StableMemory<M>, buf: &[u8]) -> Result<usize, Error> {
     let offset = get_offset(stable_memory);
-    let memory_end_bytes = offset + buf.len();
-    let memory_end_pages =
-        (memory_end_bytes as u64 + WASM_PAGE_SIZE_IN_BYTES - 1) / WASM_PAGE_SIZE_IN_BYTES;
-    let additional_pages_required = memory_end_pages.saturating_sub(capacity() as u64);
-    if additional_pages_required > 0 {
-        grow(additional_pages_required)?;
-    }
-    let capacity = capacity();
-    let write_buf = if memory_end_bytes > capacity {
-        if offset <= capacity {
-            &buf[..capacity - offset]
-        } else {
-            return Err(StableMemoryError::OutOfBounds);
+    let write_len = write_at(stable_memory, offset, buf)?;
+    set_offset(stable_memory, offset + write_len as u64);
+    Ok(write_len)
+}
+
+/// Writes each byte slice in turn to the memory location specified by the
+/// cursor.
+pub fn write_vectored<M: Memory>(
+    stable_memory: &mut StableMemory<M>,
+    bufs: &[io::IoSlice<'_>],
+) -> Result<usize, Error> {
+    let offset = get_offset(stable_memory);
+    let write_len = write_vectored_at(stable_memory, offset, bufs)?;
+    set_offset(stable_memory, offset + write_len as u64);
+    Ok(write_len)
+}
+
+impl<M: Memory> StableMemory<M> {
+    /// Creates a new handle to the given memory, positioned at byte 0.
+    pub fn new(memory: M) -> Self {
+        Self {
+            memory,
+            offset: 0,
+            growth_policy: GrowthPolicy::default(),
+            max_pages: None,
+            logical_len: false,
+            dirty_pages: None,
         }
-    } else {
-        buf
-    };
-    stable64_write(offset as u64, write_buf);
-    let new_offset = offset + write_buf.len();
-    set_offset(stable_memory, new_offset);
-    Ok(write_buf.len())
+    }
+
+    /// Enables logical length tracking.
+    ///
+    /// The highest offset written to is persisted in the first 8 bytes of the
+    /// memory, and offsets are relative to the data that follows. Seeking from
+    /// the end and reading to the end then follow the logical length, like a
+    /// file, rather than the capacity of the memory.
+    ///
+    /// The memory must either be empty or have only been written to by
+    /// handles with logical length tracking enabled.
+    pub fn with_logical_len(self) -> Self {
+        Self {
+            logical_len: true,
+            ..self
+        }
+    }
+
+    /// Sets how the memory is grown when writing past its end.
+    pub fn with_growth_policy(self, growth_policy: GrowthPolicy) -> Self {
+        Self {
+            growth_policy,
+            ..self
+        }
+    }
+
+    /// Sets the number of pages beyond which writes will not grow the memory.
+    ///
+    /// Writes that would need more pages are cut short at the end of the memory.
+    pub fn with_max_pages(self, max_pages: u64) -> Self {
+        Self {
+            max_pages: Some(max_pages),
+            ..self
+        }
+    }
+
+    /// Tracks the pages of the memory written through this handle in
+    /// `dirty_pages`.
+    ///
+    /// Writes made through other handles, or directly to the memory, are not
+    /// tracked, so every handle that writes to the memory should share the
+    /// same `DirtyPages`.
+    pub fn with_dirty_pages(self, dirty_pages: DirtyPages) -> Self {
+        Self {
+            dirty_pages: Some(dirty_pages),
+            ..self
+        }
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Gets the length of the data in bytes.
+    ///
+    /// This is the logical length when it is being tracked, and the capacity
+    /// of the memory otherwise.
+    pub fn len(&self) -> u64 {
+        data_end(self)
+    }
+
+    /// Returns `true` if there is no data.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Truncates or extends the data to `len` bytes, as with `File::set_len`.
+    ///
+    /// Extending the data fills the new bytes with zeros.
+    ///
+    /// # Panics
+    ///
+    /// Panics if logical length tracking is not enabled.
+    pub fn set_len(&self, len: u64) -> Result<(), Error> {
+        assert!(self.logical_len, "Logical length tracking is not enabled");
+        set_len(self, len)
+    }
+
+    /// Returns a copy of `len` bytes of the data, starting at `start`.
+    ///
+    /// Unlike `bytes`, this only maps the requested range.
+    pub fn read_range(&self, start: u64, len: u64) -> Result<Vec<u8>, Error> {
+        read_range(self, start, len)
+    }
+
+    /// Returns an iterator over all of the data in chunks of `chunk_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunks(&self, chunk_size: usize) -> Chunks<'_, M> {
+        self.chunks_to(u64::MAX, chunk_size)
+    }
+
+    /// Returns an iterator over the data up to `end` in chunks of `chunk_size` bytes.
+    ///
+    /// This is useful for stopping at a high-water mark rather than at the
+    /// capacity of the memory. `end` is limited to the length of the data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunks_to(&self, end: u64, chunk_size: usize) -> Chunks<'_, M> {
+        let end = end.min(data_end(self));
+        Chunks::new(&self.memory, data_start(self), end, chunk_size)
+    }
 }
 
 impl StableMemory {
     /// Returns a copy of the stable memory.
     ///
-    /// This will map the whole memory (even if not all of it has been written to).
+    /// This will map the whole memory (even if not all of it has been written to),
+    /// so consider `read_range` or `chunks` for large memories.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stable memory is larger than the heap can address.
     pub fn bytes() -> Vec<u8> {
-        bytes()
+        bytes(&Ic0StableMemory)
     }
 
     /// Gets capacity of the stable memory in bytes.
-    pub fn capacity() -> usize {
-        capacity()
+    pub fn capacity() -> u64 {
+        capacity(&Ic0StableMemory)
     }
 
     /// Attempts to grow the memory by adding new pages.
     pub fn grow(added_pages: u64) -> std::io::Result<u64> {
-        grow(added_pages).map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::Other, "Unable to grow stable memory")
-        })
+        grow(&Ic0StableMemory, added_pages).map_err(io::Error::from)
     }
 
     /// Gets current size of the stable memory in WebAssembly pages.
     pub fn size() -> u64 {
-        size()
+        size(&Ic0StableMemory)
     }
 }
 
 impl Default for StableMemory {
     fn default() -> Self {
-        Self { offset: 0 }
+        Self::new(Ic0StableMemory)
     }
 }
 
-impl std::io::Read for StableMemory {
+impl<M: Memory> std::io::Read for StableMemory<M> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        read(self, buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        read(self, buf).map_err(io::Error::from)
     }
 
-    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
-        crate::internal::default_read_to_end(self, buf).or(Ok(0)) // Read defines EOF to be success
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+        read_vectored(self, bufs).map_err(io::Error::from)
     }
 }
 
-impl std::io::Write for StableMemory {
+impl<M: Memory> std::io::Write for StableMemory<M> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        write(self, buf).map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))
+        write(self, buf).map_err(io::Error::from)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> std::io::Result<usize> {
+        write_vectored(self, bufs).map_err(io::Error::from)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        // No-op.
+        self.memory.flush();
         Ok(())
     }
 }
 
-impl std::io::Seek for StableMemory {
+impl<M: Memory> ReadAt for StableMemory<M> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        read_at(self, offset, buf).map_err(io::Error::from)
+    }
+}
+
+impl<M: Memory> WriteAt for StableMemory<M> {
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<usize> {
+        write_at(self, offset, buf).map_err(io::Error::from)
+    }
+}
+
+impl<M: Memory> std::io::Seek for StableMemory<M> {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        seek(self, pos)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Attempt to seek before byte 0"))
+        seek(self, pos).map_err(io::Error::from)
     }
 }
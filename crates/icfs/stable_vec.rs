@@ -0,0 +1,170 @@
+// A growable vector that lives in a memory rather than on the heap.
+//
+// Every element takes up the same number of bytes, so the element at an index
+// is found by multiplication and nothing needs to be loaded up front. The
+// length is kept in the header, so the vector survives upgrades as it is.
+//
+// Layout:
+// -------------------------------------------------- <- Byte 0
+// Magic "ICV"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Element size (in bytes)                ↕ 4 bytes
+// Length (in elements)                   ↕ 8 bytes
+// -------------------------------------------------- <- Byte 16
+// Element 0                              ↕ Element size
+// Element 1                              ↕ Element size
+// ...
+use crate::error::Error;
+use crate::internal::ensure_capacity;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::storable::Storable;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 3] = b"ICV";
+const LAYOUT_VERSION: u8 = 1;
+
+const LEN_OFFSET: u64 = 8;
+const HEADER_SIZE_IN_BYTES: u64 = 16;
+
+/// A vector of elements of a fixed size that lives in a memory, such as a
+/// `VirtualMemory` handed out by a `MemoryManager`.
+///
+/// Elements must encode to exactly `T::MAX_SIZE` bytes.
+pub struct StableVec<T: Storable, M: Memory = Ic0StableMemory> {
+    memory: M,
+    len: u64,
+    _marker: PhantomData<T>,
+}
+
+fn element_offset<T: Storable>(index: u64) -> u64 {
+    HEADER_SIZE_IN_BYTES + index * T::MAX_SIZE as u64
+}
+
+impl<T: Storable, M: Memory> StableVec<T, M> {
+    /// Loads the vector stored in `memory`, or creates a new empty one if
+    /// `memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` is not empty and does not hold a vector of elements
+    /// of the size of `T`.
+    pub fn init(memory: M) -> Result<Self, Error> {
+        if memory.size() == 0 {
+            ensure_capacity(&memory, HEADER_SIZE_IN_BYTES)?;
+            let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+            header[0..3].copy_from_slice(MAGIC);
+            header[3] = LAYOUT_VERSION;
+            header[4..8].copy_from_slice(&T::MAX_SIZE.to_le_bytes());
+            memory.write(0, &header);
+            return Ok(Self {
+                memory,
+                len: 0,
+                _marker: PhantomData,
+            });
+        }
+
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        memory.read(0, &mut header);
+        assert_eq!(&header[0..3], MAGIC, "Memory does not hold a vector");
+        assert_eq!(header[3], LAYOUT_VERSION, "Unsupported vector layout version");
+        assert_eq!(
+            u32::from_le_bytes(header[4..8].try_into().unwrap()),
+            T::MAX_SIZE,
+            "Element size does not match"
+        );
+        Ok(Self {
+            memory,
+            len: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Gets the number of elements in the vector.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the vector has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the element at `index`, or `None` if it is out of bounds.
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+        let mut bytes = vec![0; T::MAX_SIZE as usize];
+        self.memory.read(element_offset::<T>(index), &mut bytes);
+        Some(T::from_bytes(&bytes))
+    }
+
+    /// Replaces the element at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: u64, value: &T) {
+        assert!(index < self.len, "Index out of bounds");
+        self.memory
+            .write(element_offset::<T>(index), &Self::encode(value));
+    }
+
+    /// Appends an element to the end of the vector, growing the memory if
+    /// needed.
+    pub fn push(&mut self, value: &T) -> Result<(), Error> {
+        let bytes = Self::encode(value);
+        let offset = element_offset::<T>(self.len);
+        let end = offset
+            .checked_add(bytes.len() as u64)
+            .ok_or(Error::OffsetOverflow)?;
+        ensure_capacity(&self.memory, end)?;
+        self.memory.write(offset, &bytes);
+        self.set_len(self.len + 1);
+        Ok(())
+    }
+
+    /// Removes the last element and returns it, or `None` if the vector is
+    /// empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let value = self.get(self.len.checked_sub(1)?)?;
+        self.set_len(self.len - 1);
+        Some(value)
+    }
+
+    /// Shortens the vector to `len` elements, doing nothing if it is already
+    /// no longer than that.
+    ///
+    /// The memory is not shrunk.
+    pub fn truncate(&mut self, len: u64) {
+        if len < self.len {
+            self.set_len(len);
+        }
+    }
+
+    /// Iterates over the elements, reading each one as it is reached.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).filter_map(move |index| self.get(index))
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.len = len;
+        self.memory.write(LEN_OFFSET, &len.to_le_bytes());
+    }
+
+    fn encode(value: &T) -> Vec<u8> {
+        let bytes = value.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            T::MAX_SIZE as usize,
+            "Element does not encode to its fixed size"
+        );
+        bytes
+    }
+}
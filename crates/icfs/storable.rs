@@ -0,0 +1,59 @@
+use std::convert::TryInto;
+
+/// A type that can be stored in a `StableVec` or a `StableBTreeMap`, encoded
+/// in at most `MAX_SIZE` bytes.
+pub trait Storable: Sized {
+    /// The maximum number of bytes returned by `to_bytes`.
+    ///
+    /// This sizes the space set aside for each value in memory, so it must
+    /// not change once values have been stored.
+    const MAX_SIZE: u32;
+
+    /// Encodes the value.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Decodes a value encoded by `to_bytes`.
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! storable_integers {
+    ($($ty:ty),*) => {
+        $(
+            impl Storable for $ty {
+                const MAX_SIZE: u32 = std::mem::size_of::<$ty>() as u32;
+
+                fn to_bytes(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().expect("Unexpected number of bytes"))
+                }
+            }
+        )*
+    };
+}
+
+storable_integers!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> Storable for [u8; N] {
+    const MAX_SIZE: u32 = N as u32;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        bytes.try_into().expect("Unexpected number of bytes")
+    }
+}
+
+impl Storable for () {
+    const MAX_SIZE: u32 = 0;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn from_bytes(_bytes: &[u8]) -> Self {}
+}
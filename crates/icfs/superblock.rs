@@ -0,0 +1,303 @@
+// A header at the start of a memory that describes how the rest of it is laid
+// out.
+//
+// The superblock tells a canister, on `post_upgrade` or otherwise, whether the
+// memory holds an icfs layout at all, which version of its own layout the
+// memory was written with, and where each of its named regions lives. Regions
+// are laid out one after the other, straight after the superblock. Migrations
+// registered by the canister bring the memory from an older version of its
+// layout up to the current one, one version at a time.
+//
+// Layout:
+// -------------------------------------------------- <- Byte 0
+// Magic "ICF"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Version of the canister layout         ↕ 4 bytes
+// Number of regions                      ↕ 4 bytes
+// Reserved space                         ↕ 20 bytes
+// -------------------------------------------------- <- Byte 32
+// Name of region 0 (zero-padded UTF-8)   ↕ 16 bytes
+// Base of region 0 (in bytes)            ↕ 8 bytes
+// Length of region 0 (in bytes)          ↕ 8 bytes
+// Name of region 1                       ↕ 16 bytes
+// ...
+// Length of region 126 (in bytes)        ↕ 8 bytes
+// -------------------------------------------------- <- Byte 4096
+// Region 0                               ↕ N bytes
+// Region 1                               ↕ M bytes
+// ...
+use crate::error::Error;
+use crate::internal::ensure_capacity;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::region::Region;
+use crate::stable_memory::capacity;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+
+const MAGIC: &[u8; 3] = b"ICF";
+const LAYOUT_VERSION: u8 = 1;
+
+/// The number of bytes taken up by the superblock at the start of a memory.
+pub const SUPERBLOCK_SIZE_IN_BYTES: u64 = 4096;
+
+/// The maximum number of regions a superblock can describe.
+pub const MAX_NUM_REGIONS: usize = 127;
+
+/// The maximum length of the name of a region, in bytes.
+pub const MAX_REGION_NAME_LEN: usize = 16;
+
+const VERSION_OFFSET: u64 = 4;
+const NUM_REGIONS_OFFSET: u64 = 8;
+const HEADER_SIZE_IN_BYTES: u64 = 32;
+const REGION_ENTRY_SIZE_IN_BYTES: u64 = 32;
+
+/// A region recorded in the superblock.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct RegionEntry {
+    name: String,
+    base: u64,
+    len: u64,
+}
+
+/// The header at the start of a memory that records the version of its layout
+/// and the named regions that make up the rest of it.
+///
+/// Changes are written through to the memory as they are made, so there is
+/// nothing to save on `pre_upgrade`.
+#[derive(Clone, Debug)]
+pub struct Superblock<M: Memory = Ic0StableMemory> {
+    memory: M,
+    version: u32,
+    regions: Vec<RegionEntry>,
+}
+
+/// A step that migrates a memory from one version of its layout to the next.
+type Migration<M> = Box<dyn Fn(&mut Superblock<M>) -> Result<(), Error>>;
+
+/// The migrations a canister knows of, keyed by the version of the layout that
+/// each of them migrates from.
+pub struct Migrations<M: Memory = Ic0StableMemory> {
+    steps: BTreeMap<u32, Migration<M>>,
+}
+
+impl<M: Memory + Clone> Superblock<M> {
+    /// Loads the superblock stored in `memory`, or creates a new one with the
+    /// given version and no regions if `memory` is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `memory` is not empty and does not hold a superblock, so that
+    /// a memory laid out some other way is never written over.
+    pub fn init(memory: M, version: u32) -> Result<Self, Error> {
+        if memory.size() == 0 {
+            return Self::create(memory, version);
+        }
+        Ok(Self::load(memory).expect("Memory does not hold a superblock"))
+    }
+
+    /// Creates a new superblock with the given version and no regions,
+    /// writing over whatever is at the start of `memory`.
+    pub fn create(memory: M, version: u32) -> Result<Self, Error> {
+        ensure_capacity(&memory, SUPERBLOCK_SIZE_IN_BYTES)?;
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        header[0..3].copy_from_slice(MAGIC);
+        header[3] = LAYOUT_VERSION;
+        header[4..8].copy_from_slice(&version.to_le_bytes());
+        memory.write(0, &header);
+        Ok(Self {
+            memory,
+            version,
+            regions: vec![],
+        })
+    }
+
+    /// Loads the superblock stored in `memory`, or returns `None` if `memory`
+    /// does not start with one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the superblock was written with a layout version that is not
+    /// supported.
+    pub fn load(memory: M) -> Option<Self> {
+        if capacity(&memory) < SUPERBLOCK_SIZE_IN_BYTES {
+            return None;
+        }
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        memory.read(0, &mut header);
+        if &header[0..3] != MAGIC {
+            return None;
+        }
+        assert_eq!(
+            header[3], LAYOUT_VERSION,
+            "Unsupported superblock layout version"
+        );
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let num_regions = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        assert!(num_regions <= MAX_NUM_REGIONS, "Superblock is corrupted");
+
+        let mut entries = vec![0; num_regions * REGION_ENTRY_SIZE_IN_BYTES as usize];
+        memory.read(HEADER_SIZE_IN_BYTES, &mut entries);
+        let regions = entries
+            .chunks_exact(REGION_ENTRY_SIZE_IN_BYTES as usize)
+            .map(|entry| {
+                let name = &entry[..MAX_REGION_NAME_LEN];
+                let name_len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+                RegionEntry {
+                    name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+                    base: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+                    len: u64::from_le_bytes(entry[24..32].try_into().unwrap()),
+                }
+            })
+            .collect();
+
+        Some(Self {
+            memory,
+            version,
+            regions,
+        })
+    }
+
+    /// Gets the version of the layout of the memory, as set by the canister.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Sets the version of the layout of the memory.
+    ///
+    /// Migrations do this themselves, so there is usually no need to call it.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+        self.memory.write(VERSION_OFFSET, &version.to_le_bytes());
+    }
+
+    /// Returns the region with the given name, if there is one.
+    pub fn region(&self, name: &str) -> Option<Region<M>> {
+        self.regions
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| Region::new(self.memory.clone(), entry.base, entry.len))
+    }
+
+    /// Returns every region along with its name, in the order they were
+    /// added.
+    pub fn regions(&self) -> Vec<(&str, Region<M>)> {
+        self.regions
+            .iter()
+            .map(|entry| {
+                let region = Region::new(self.memory.clone(), entry.base, entry.len);
+                (entry.name.as_str(), region)
+            })
+            .collect()
+    }
+
+    /// Adds a region of `len` bytes with the given name after the last one,
+    /// growing the memory to fit it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is empty, longer than `MAX_REGION_NAME_LEN` bytes,
+    /// contains a NUL character or is already taken, or if there are already
+    /// `MAX_NUM_REGIONS` regions.
+    pub fn add_region(&mut self, name: &str, len: u64) -> Result<Region<M>, Error> {
+        assert!(
+            !name.is_empty() && name.len() <= MAX_REGION_NAME_LEN && !name.contains('\0'),
+            "Invalid region name"
+        );
+        assert!(self.region(name).is_none(), "Region already exists");
+        assert!(self.regions.len() < MAX_NUM_REGIONS, "Too many regions");
+
+        let base = self
+            .regions
+            .last()
+            .map_or(SUPERBLOCK_SIZE_IN_BYTES, |entry| entry.base + entry.len);
+        let end = base.checked_add(len).ok_or(Error::OffsetOverflow)?;
+        ensure_capacity(&self.memory, end)?;
+
+        let mut entry = [0; REGION_ENTRY_SIZE_IN_BYTES as usize];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        entry[16..24].copy_from_slice(&base.to_le_bytes());
+        entry[24..32].copy_from_slice(&len.to_le_bytes());
+        let index = self.regions.len() as u64;
+        self.memory
+            .write(HEADER_SIZE_IN_BYTES + index * REGION_ENTRY_SIZE_IN_BYTES, &entry);
+        // The entry is only counted once it has been written in full.
+        self.memory
+            .write(NUM_REGIONS_OFFSET, &(index as u32 + 1).to_le_bytes());
+
+        self.regions.push(RegionEntry {
+            name: name.to_string(),
+            base,
+            len,
+        });
+        Ok(Region::new(self.memory.clone(), base, len))
+    }
+
+    /// Runs the registered migrations, one after the other, starting from the
+    /// current version of the layout until there are none left for the
+    /// version reached. Returns the version reached.
+    ///
+    /// The version is bumped as soon as each migration succeeds. If one
+    /// fails, its error is returned and the version is left at the one it
+    /// migrates from, so that it is run again next time.
+    pub fn migrate(&mut self, migrations: &Migrations<M>) -> Result<u32, Error> {
+        while let Some(migration) = migrations.steps.get(&self.version) {
+            migration(self)?;
+            self.set_version(self.version + 1);
+        }
+        Ok(self.version)
+    }
+
+    /// Returns a reference to the underlying memory.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M: Memory> Migrations<M> {
+    pub fn new() -> Self {
+        Self {
+            steps: BTreeMap::new(),
+        }
+    }
+
+    /// Registers a migration from version `from_version` of the layout to the
+    /// next version.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a migration from `from_version` has already been registered,
+    /// or if `from_version` is `u32::MAX`.
+    pub fn register(
+        mut self,
+        from_version: u32,
+        migration: impl Fn(&mut Superblock<M>) -> Result<(), Error> + 'static,
+    ) -> Self {
+        assert!(from_version < u32::MAX, "There is no version to migrate to");
+        assert!(
+            self.steps.insert(from_version, Box::new(migration)).is_none(),
+            "Migration already registered"
+        );
+        self
+    }
+
+    /// Gets the version that the registered migrations lead up to, if any
+    /// have been registered.
+    pub fn latest_version(&self) -> Option<u32> {
+        self.steps.keys().next_back().map(|version| version + 1)
+    }
+}
+
+impl<M: Memory> Default for Migrations<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for Migrations<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migrations")
+            .field("from_versions", &self.steps.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
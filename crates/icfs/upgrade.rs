@@ -0,0 +1,208 @@
+// Saves heap state to a `StableMemory` in `pre_upgrade` and restores it in
+// `post_upgrade`.
+//
+// The value is streamed through a small buffer as it is encoded and decoded,
+// so neither side ever holds the whole encoding in the heap. Its length is
+// only known once it has been written, so the header is written last: a value
+// whose saving did not finish reads as if nothing had been saved at all.
+//
+// Layout:
+// -------------------------------------------------- <- Cursor of the handle
+// Magic "ICU"                            ↕ 3 bytes
+// Layout version                         ↕ 1 byte
+// Schema version                         ↕ 4 bytes
+// Length of the value (in bytes)         ↕ 8 bytes
+// -------------------------------------------------- <- Cursor + 16 bytes
+// Encoded value                          ↕ N bytes
+use crate::error::Error;
+use crate::memory::{Ic0StableMemory, Memory};
+use crate::positioned::{ReadAt, WriteAt};
+use crate::stable_memory::StableMemory;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8; 3] = b"ICU";
+const LAYOUT_VERSION: u8 = 1;
+
+const HEADER_SIZE_IN_BYTES: u64 = 16;
+
+// The number of bytes held in the heap at a time while streaming a value.
+const BUFFER_SIZE_IN_BYTES: usize = 64 * 1024;
+
+/// Streams an encoded value into a `StableMemory`, starting at its cursor.
+///
+/// Anything that encodes to an `io::Write` can be saved this way, such as
+/// `candid::utils::write_args`. The value only counts as saved once `finish`
+/// has been called.
+pub struct UpgradeWriter<'a, M: Memory = Ic0StableMemory> {
+    writer: BufWriter<&'a mut StableMemory<M>>,
+    start: u64,
+    schema_version: u32,
+    len: u64,
+}
+
+/// Streams an encoded value saved by an `UpgradeWriter` back out of a
+/// `StableMemory`, starting at its cursor.
+///
+/// Reads end at the end of the value.
+pub struct UpgradeReader<'a, M: Memory = Ic0StableMemory> {
+    reader: BufReader<io::Take<&'a mut StableMemory<M>>>,
+    schema_version: u32,
+    len: u64,
+}
+
+impl<'a, M: Memory> UpgradeWriter<'a, M> {
+    /// Starts saving a value with the given schema version at the cursor of
+    /// `stable_memory`.
+    pub fn new(stable_memory: &'a mut StableMemory<M>, schema_version: u32) -> io::Result<Self> {
+        let start = stable_memory.stream_position()?;
+        // Clears out the header of anything saved here before, so that it
+        // cannot be mistaken for this value if saving does not finish.
+        stable_memory.write_all(&[0; HEADER_SIZE_IN_BYTES as usize])?;
+        Ok(Self {
+            writer: BufWriter::with_capacity(BUFFER_SIZE_IN_BYTES, stable_memory),
+            start,
+            schema_version,
+            len: 0,
+        })
+    }
+
+    /// Encodes `value` with CBOR and streams it in.
+    pub fn serialize<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        ciborium::ser::into_writer(value, &mut *self).map_err(|error| match error {
+            ciborium::ser::Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        })
+    }
+
+    /// Writes out what is left of the value and then the header, leaving the
+    /// cursor at the end of the value. Returns the length of the value in
+    /// bytes.
+    pub fn finish(self) -> io::Result<u64> {
+        let stable_memory = self.writer.into_inner().map_err(|error| error.into_error())?;
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        header[0..3].copy_from_slice(MAGIC);
+        header[3] = LAYOUT_VERSION;
+        header[4..8].copy_from_slice(&self.schema_version.to_le_bytes());
+        header[8..16].copy_from_slice(&self.len.to_le_bytes());
+        stable_memory.write_all_at(self.start, &header)?;
+        Ok(self.len)
+    }
+}
+
+impl<'a, M: Memory> Write for UpgradeWriter<'a, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<'a, M: Memory> UpgradeReader<'a, M> {
+    /// Starts restoring the value saved at the cursor of `stable_memory`.
+    ///
+    /// Fails with `Error::NoSavedValue` if no value has been saved there.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value was saved with a layout version that is not
+    /// supported.
+    pub fn new(stable_memory: &'a mut StableMemory<M>) -> io::Result<Self> {
+        let start = stable_memory.stream_position()?;
+        let mut header = [0; HEADER_SIZE_IN_BYTES as usize];
+        let header_len = stable_memory.read_at(start, &mut header)?;
+        if header_len < header.len() || &header[0..3] != MAGIC {
+            return Err(Error::NoSavedValue { offset: start }.into());
+        }
+        assert_eq!(
+            header[3], LAYOUT_VERSION,
+            "Unsupported saved value layout version"
+        );
+        let schema_version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        stable_memory.seek(SeekFrom::Current(HEADER_SIZE_IN_BYTES as i64))?;
+        Ok(Self {
+            reader: BufReader::with_capacity(BUFFER_SIZE_IN_BYTES, stable_memory.take(len)),
+            schema_version,
+            len,
+        })
+    }
+
+    /// Gets the schema version the value was saved with.
+    ///
+    /// Check this before decoding, to pick the type the value was saved as.
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Gets the length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Decodes the value from CBOR as it is streamed out.
+    ///
+    /// Fails if the value does not decode as a `T`, or if there are bytes left
+    /// over once it has been decoded.
+    pub fn deserialize<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        let value = ciborium::de::from_reader(&mut *self).map_err(|error| match error {
+            ciborium::de::Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        })?;
+        if self.read(&mut [0])? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Bytes left over after the saved value",
+            ));
+        }
+        Ok(value)
+    }
+}
+
+impl<'a, M: Memory> Read for UpgradeReader<'a, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Saves `value`, encoded with CBOR, along with its schema version at the
+/// cursor of `stable_memory`, as when called from `pre_upgrade`.
+///
+/// The cursor is left at the end of the value, so that several values can be
+/// saved one after the other.
+pub fn stable_save<M: Memory, T: Serialize>(
+    stable_memory: &mut StableMemory<M>,
+    schema_version: u32,
+    value: &T,
+) -> io::Result<()> {
+    let mut writer = UpgradeWriter::new(stable_memory, schema_version)?;
+    writer.serialize(value)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Restores a value saved with `stable_save` at the cursor of
+/// `stable_memory`, as when called from `post_upgrade`, returning its schema
+/// version along with it.
+///
+/// The cursor is left at the end of the value. To restore a value saved with
+/// an older schema, use an `UpgradeReader` to check the schema version before
+/// decoding.
+pub fn stable_restore<M: Memory, T: DeserializeOwned>(
+    stable_memory: &mut StableMemory<M>,
+) -> io::Result<(u32, T)> {
+    let mut reader = UpgradeReader::new(stable_memory)?;
+    let value = reader.deserialize()?;
+    Ok((reader.schema_version(), value))
+}
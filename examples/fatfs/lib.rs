@@ -1,66 +1,325 @@
-use ic_cdk_macros::{query, update};
-use std::io::{Read, Write};
-
-#[cfg(target_arch = "wasm32")]
+use candid::CandidType;
+use ic_cdk_macros::{post_upgrade, pre_upgrade, query, update};
+use icfs::{Memory, WriteAt};
+use serde::Deserialize;
 use std::convert::TryInto;
+use std::io::{Read, Write};
 
-// type FileSystem = fatfs::FileSystem<
-//     fatfs::StdIoWrapper<fscommon::BufStream<icfs::StableMemory>>,
-//     icfs_fatfs::TimeProvider,
-//     fatfs::LossyOemCpConverter,
-// >;
 type FileSystem = fatfs::FileSystem<
-    fatfs::StdIoWrapper<icfs::StableMemory>,
+    fatfs::StdIoWrapper<icfs::ChecksummedRegion<icfs::BlockCache<icfs::InstrumentedMemory>>>,
     icfs_fatfs::TimeProvider,
     fatfs::LossyOemCpConverter,
 >;
 
 type Dir<'a> = fatfs::Dir<
     'a,
-    fatfs::StdIoWrapper<icfs::StableMemory>,
+    fatfs::StdIoWrapper<icfs::ChecksummedRegion<icfs::BlockCache<icfs::InstrumentedMemory>>>,
     icfs_fatfs::TimeProvider,
     fatfs::LossyOemCpConverter,
 >;
 
+const SECTOR_SIZE_IN_BYTES: u64 = 512;
+// The size of the CRC-32C that `ChecksummedRegion` keeps for each sector.
+const CHECKSUM_SIZE_IN_BYTES: u64 = 4;
+
+// Each cached block holds several sectors.
+const CACHE_BLOCK_SIZE_IN_BYTES: u64 = 4096;
+const CACHE_MAX_BLOCKS: usize = 256;
+
+// The version of the layout of stable memory recorded in the superblock.
+const LAYOUT_VERSION: u32 = 1;
+const VOLUME_REGION_NAME: &str = "fatfs";
+
+// The layout from before the superblock, in which the volume was a
+// `ChecksummedRegion` at offset 0 with as many sectors as fit in the pages of
+// stable memory it was first formatted in.
+const LEGACY_LAYOUT_VERSION: u32 = 0;
+const LEGACY_SECTORS_PER_PAGE: u64 = icfs::WASM_PAGE_SIZE_IN_BYTES / SECTOR_SIZE_IN_BYTES;
+// The signature at the end of the boot sector of a FAT volume, and where its
+// total number of sectors is kept, in 16 bits or, if that is 0, in 32 bits.
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const TOTAL_SECTORS_16_OFFSET: usize = 19;
+const TOTAL_SECTORS_32_OFFSET: usize = 32;
+// The volume is moved in chunks when migrating from the legacy layout.
+const MIGRATION_CHUNK_SIZE_IN_BYTES: u64 = icfs::WASM_PAGE_SIZE_IN_BYTES;
+
+// Well within the limit on the size of a reply.
+const IMAGE_CHUNK_SIZE_IN_BYTES: usize = 1024 * 1024;
+
 thread_local! {
-    // static STABLE_MEMORY: std::cell::RefCell<fscommon::BufStream<icfs::StableMemory>>
-    //     = std::cell::RefCell::new(fscommon::BufStream::new(icfs::StableMemory::default()));
-    static STABLE_MEMORY: std::cell::RefCell<icfs::StableMemory>
-        = std::cell::RefCell::new(icfs::StableMemory::default());
+    // Counts the calls that reach stable memory, i.e. those the cache misses.
+    static STABLE_MEMORY: icfs::InstrumentedMemory =
+        icfs::InstrumentedMemory::new(icfs::Ic0StableMemory);
+    static CACHE: icfs::BlockCache<icfs::InstrumentedMemory> = icfs::BlockCache::new(
+        STABLE_MEMORY.with(|stable_memory| stable_memory.clone()),
+        CACHE_BLOCK_SIZE_IN_BYTES,
+        CACHE_MAX_BLOCKS,
+    );
+    static FS: std::cell::RefCell<FileSystem> = std::cell::RefCell::new(init_fs().unwrap());
+    static EXPORT: std::cell::RefCell<Option<icfs::ImageExport<icfs::InstrumentedMemory>>>
+        = std::cell::RefCell::new(None);
+    static IMPORT: std::cell::RefCell<Option<icfs::ImageImport<icfs::InstrumentedMemory>>>
+        = std::cell::RefCell::new(None);
+}
+
+/// A chunk of an image of stable memory, as sent between canisters.
+#[derive(CandidType, Deserialize)]
+struct ImageChunk {
+    offset: u64,
+    total_len: u64,
+    bytes: Vec<u8>,
+    sha256: Vec<u8>,
+}
+
+impl From<icfs::ImageChunk> for ImageChunk {
+    fn from(chunk: icfs::ImageChunk) -> Self {
+        Self {
+            offset: chunk.offset,
+            total_len: chunk.total_len,
+            bytes: chunk.bytes,
+            sha256: chunk.sha256.to_vec(),
+        }
+    }
+}
+
+/// Finds a volume laid out as in `LEGACY_LAYOUT_VERSION` from `base` onwards,
+/// returning the number of pages it was formatted in.
+///
+/// Those pages are not recorded anywhere, but they set both the number of
+/// sectors of the volume and the size of its checksum table, so the boot
+/// sector of the volume is only found where the checksum table of the right
+/// number of pages ends. Writing to the volume can have grown stable memory by
+/// up to the size of that table, i.e. by 1/128.
+fn find_legacy_volume<M: Memory>(memory: &M, base: u64) -> Option<u64> {
+    let capacity = memory.size() * icfs::WASM_PAGE_SIZE_IN_BYTES;
+    let max_pages = capacity.saturating_sub(base) / icfs::WASM_PAGE_SIZE_IN_BYTES + 1;
+    let min_pages = (max_pages - max_pages / LEGACY_SECTORS_PER_PAGE)
+        .saturating_sub(2)
+        .max(1);
+    (min_pages..=max_pages).rev().find(|pages| {
+        let num_sectors = pages * LEGACY_SECTORS_PER_PAGE;
+        let boot_sector_offset = base + num_sectors * CHECKSUM_SIZE_IN_BYTES;
+        if boot_sector_offset + SECTOR_SIZE_IN_BYTES > capacity {
+            return false;
+        }
+        let mut boot_sector = [0; SECTOR_SIZE_IN_BYTES as usize];
+        memory.read(boot_sector_offset, &mut boot_sector);
+        let total_sectors_16 = u16::from_le_bytes([
+            boot_sector[TOTAL_SECTORS_16_OFFSET],
+            boot_sector[TOTAL_SECTORS_16_OFFSET + 1],
+        ]);
+        let total_sectors = if total_sectors_16 != 0 {
+            total_sectors_16 as u64
+        } else {
+            let mut total_sectors_32 = [0; 4];
+            total_sectors_32.copy_from_slice(
+                &boot_sector[TOTAL_SECTORS_32_OFFSET..TOTAL_SECTORS_32_OFFSET + 4],
+            );
+            u32::from_le_bytes(total_sectors_32) as u64
+        };
+        boot_sector[BOOT_SIGNATURE_OFFSET..] == BOOT_SIGNATURE && total_sectors == num_sectors
+    })
+}
+
+/// Moves a volume laid out as in `LEGACY_LAYOUT_VERSION` out of the way of a
+/// superblock, and writes one with that version in front of it, for
+/// `migrations` to take it from there.
+///
+/// Fails if stable memory does not hold such a volume, rather than writing
+/// over whatever it does hold.
+fn adopt_legacy_volume(
+    stable_memory: icfs::InstrumentedMemory,
+) -> std::io::Result<icfs::Superblock<icfs::InstrumentedMemory>> {
+    let pages = find_legacy_volume(&stable_memory, 0).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Stable memory holds neither a superblock nor a volume from before it",
+        )
+    })?;
+    // Sectors past the end of stable memory have never been written, and read
+    // as zeros wherever they are.
+    let num_sectors = pages * LEGACY_SECTORS_PER_PAGE;
+    let volume_end = num_sectors * (CHECKSUM_SIZE_IN_BYTES + SECTOR_SIZE_IN_BYTES);
+    let mut end = volume_end.min(stable_memory.size() * icfs::WASM_PAGE_SIZE_IN_BYTES);
+
+    // Copy from the end, so that nothing is written over before it is copied.
+    let destination = icfs::StableMemory::new(stable_memory.clone());
+    let mut chunk = vec![0; MIGRATION_CHUNK_SIZE_IN_BYTES as usize];
+    while end > 0 {
+        let start = end.saturating_sub(MIGRATION_CHUNK_SIZE_IN_BYTES);
+        let chunk = &mut chunk[..(end - start) as usize];
+        stable_memory.read(start, chunk);
+        destination.write_all_at(icfs::SUPERBLOCK_SIZE_IN_BYTES + start, chunk)?;
+        end = start;
+    }
+
+    Ok(icfs::Superblock::create(
+        stable_memory,
+        LEGACY_LAYOUT_VERSION,
+    )?)
+}
+
+fn migrations() -> icfs::Migrations<icfs::InstrumentedMemory> {
+    // The volume moved by `adopt_legacy_volume` becomes the volume region,
+    // with as many sectors as before, so that its sectors stay where they are.
+    icfs::Migrations::new().register(LEGACY_LAYOUT_VERSION, |superblock| {
+        let pages = find_legacy_volume(superblock.memory(), icfs::SUPERBLOCK_SIZE_IN_BYTES)
+            .ok_or(icfs::Error::Corrupted { block: 0 })?;
+        let num_sectors = pages * LEGACY_SECTORS_PER_PAGE;
+        let len =
+            SECTOR_SIZE_IN_BYTES + num_sectors * (SECTOR_SIZE_IN_BYTES + CHECKSUM_SIZE_IN_BYTES);
+        superblock.add_region(VOLUME_REGION_NAME, len).map(|_| ())
+    })
+}
 
-    static FS: std::cell::RefCell<FileSystem> = {
-        let fs: std::io::Result<FileSystem> = STABLE_MEMORY.with(|stable_memory| {
-            let stable_memory = *stable_memory.borrow();
+fn init_fs() -> std::io::Result<FileSystem> {
+    // Stable memory that already holds a superblock, whether from before an
+    // upgrade or from `import_image`, is mounted as it is. So is a volume from
+    // before the superblock, once it has been migrated.
+    let stable_memory = STABLE_MEMORY.with(|stable_memory| stable_memory.clone());
+    let mut superblock = match icfs::Superblock::load(stable_memory.clone()) {
+        Some(superblock) => superblock,
+        None if stable_memory.size() == 0 => {
+            icfs::Superblock::create(stable_memory, LAYOUT_VERSION)?
+        }
+        None => adopt_legacy_volume(stable_memory)?,
+    };
+    superblock.migrate(&migrations())?;
 
+    let (volume_region, formatted) = match superblock.region(VOLUME_REGION_NAME) {
+        Some(volume_region) => (volume_region, true),
+        None => {
             #[cfg(target_arch = "wasm32")]
-            let memory_pages = core::arch::wasm32::memory_size(0)
+            let memory_pages: u64 = core::arch::wasm32::memory_size(0)
                 .try_into()
                 .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
 
             #[cfg(not(target_arch = "wasm32"))]
-            let memory_pages = 19;
+            let memory_pages: u64 = 19;
 
-            icfs::StableMemory::grow(memory_pages)?;
+            let volume_region = superblock
+                .add_region(VOLUME_REGION_NAME, memory_pages * icfs::WASM_PAGE_SIZE_IN_BYTES)?;
+            (volume_region, false)
+        }
+    };
 
-            // TODO
-            // let stable_memory = fscommon::BufStream::new(stable_memory);
+    // Each sector is checksummed, so that a volume that has been scribbled
+    // over fails loudly rather than serving garbage. The checksum table comes
+    // first and is rounded up to whole sectors, so leave a sector spare for it.
+    let num_sectors = volume_region.len().saturating_sub(SECTOR_SIZE_IN_BYTES)
+        / (SECTOR_SIZE_IN_BYTES + CHECKSUM_SIZE_IN_BYTES);
+    let volume = icfs::ChecksummedRegion::new(
+        CACHE.with(|cache| cache.clone()),
+        volume_region.base(),
+        SECTOR_SIZE_IN_BYTES,
+        num_sectors,
+    );
 
-            fatfs::format_volume(
-                &mut fatfs::StdIoWrapper::from(stable_memory),
-                fatfs::FormatVolumeOptions::new(),
-            )?;
+    if !formatted {
+        fatfs::format_volume(
+            &mut fatfs::StdIoWrapper::from(volume.clone()),
+            fatfs::FormatVolumeOptions::new(),
+        )?;
+    }
 
-            let options = fatfs::FsOptions::new()
-                .time_provider(icfs_fatfs::TimeProvider::new())
-                .update_accessed_date(true);
+    let options = fatfs::FsOptions::new()
+        .time_provider(icfs_fatfs::TimeProvider::new())
+        .update_accessed_date(true);
 
-            let fs = fatfs::FileSystem::new(stable_memory, options)?;
+    let fs = fatfs::FileSystem::new(volume, options)?;
 
-            Ok(fs)
-        });
+    Ok(fs)
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    CACHE.with(|cache| cache.flush());
+}
 
-        std::cell::RefCell::new(fs.unwrap())
+/// Mounts the file system, migrating stable memory first if it was written by
+/// an older version, so that an upgrade that cannot do so is rolled back
+/// rather than leaving a canister that traps on every call.
+#[post_upgrade]
+fn post_upgrade() {
+    FS.with(|_| ());
+}
+
+/// Exports the chunk of an image of stable memory at `offset`, or returns
+/// nothing once the whole image has been exported.
+///
+/// An offset of 0 starts a new export, and every other offset must follow on
+/// from the last chunk exported. The last chunk can also be asked for again,
+/// in case its reply was lost. Changes made to the file system before the
+/// export is done may or may not be included in the image.
+#[update]
+fn export_image(offset: u64) -> Option<ImageChunk> {
+    CACHE.with(|cache| cache.flush());
+    EXPORT.with(|export| {
+        let mut export = export.borrow_mut();
+        // Asking for the first chunk again simply starts over.
+        if offset == 0 {
+            let stable_memory = STABLE_MEMORY.with(|stable_memory| stable_memory.clone());
+            *export = Some(icfs::ImageExport::new(stable_memory));
+        }
+        let in_progress = export.as_mut().expect("No export is in progress");
+        in_progress
+            .export_chunk(offset, IMAGE_CHUNK_SIZE_IN_BYTES)
+            .unwrap()
+            .map(ImageChunk::from)
+    })
+}
+
+/// Imports the next chunk of an image exported with `export_image`, and
+/// returns the offset of the chunk expected next.
+///
+/// A chunk that does not follow on from the last one imported, such as one
+/// that was sent again, is ignored, so the offset returned says where to carry
+/// on from. Images can only be imported into a canister whose file system has
+/// not been used yet, and the file system should not be used until every
+/// chunk has been imported.
+#[update]
+fn import_image(chunk: ImageChunk) -> u64 {
+    let sha256: [u8; 32] = chunk
+        .sha256
+        .try_into()
+        .expect("SHA-256 must be 32 bytes long");
+    let chunk = icfs::ImageChunk {
+        offset: chunk.offset,
+        total_len: chunk.total_len,
+        bytes: chunk.bytes,
+        sha256,
     };
+    IMPORT.with(|import| {
+        let mut import = import.borrow_mut();
+        if import.is_none() {
+            assert_eq!(chunk.offset, 0, "No import is in progress");
+            assert_eq!(icfs::StableMemory::size(), 0, "Stable memory is already in use");
+            let stable_memory = STABLE_MEMORY.with(|stable_memory| stable_memory.clone());
+            *import = Some(icfs::ImageImport::new(stable_memory));
+        }
+        // Kept once done, so that chunks sent again are still answered.
+        let in_progress = import.as_mut().unwrap();
+        match in_progress.import_chunk(&chunk) {
+            Ok(()) | Err(icfs::Error::UnexpectedChunk { .. }) => in_progress.offset(),
+            Err(error) => panic!("{}", error),
+        }
+    })
+}
+
+/// Returns counts of the calls made to stable memory, the bytes they moved and
+/// the instructions they took, in the Prometheus text exposition format.
+///
+/// Only calls made during update calls are counted: the counts are kept on the
+/// heap, and changes that queries such as `cat` and `ls` make to it are
+/// discarded once they reply.
+#[query]
+fn metrics() -> String {
+    STABLE_MEMORY.with(|stable_memory| {
+        stable_memory
+            .metrics()
+            .to_prometheus("icfs_stable_memory")
+    })
 }
 
 fn open_dir_path<'a>(fs: &'a FileSystem, path: &str) -> std::io::Result<Dir<'a>> {
@@ -2,10 +2,12 @@
 // * https://users.rust-lang.org/t/existing-tests-for-read-write-and-seek-traits/72991/2
 // * https://github.com/rust-lang/rust/blob/a2ebd5a1f12f4242edf66cbbd471c421bec62753/library/std/src/io/cursor/tests.rs
 
+#![feature(can_vector)]
 #![feature(io_slice_advance)]
 #![feature(write_all_vectored)]
 
 use ic_cdk_macros::{init, query, update};
+use icfs::{Memory, ReadAt, WriteAt};
 use std::io::{IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
 
 thread_local! {
@@ -16,10 +18,13 @@ thread_local! {
 fn setup() {
     STABLE_MEMORY.with(|stable_memory| {
         let mut stable_memory = *stable_memory.borrow();
+        if icfs::StableMemory::size() == 0 {
+            icfs::StableMemory::grow(1).unwrap();
+        }
         let capacity = icfs::StableMemory::capacity();
-        let b: &[_] = &vec![0; capacity];
+        let b: &[_] = &vec![0; capacity as usize];
 
-        ic_cdk::api::stable::stable64_write(0, &b);
+        icfs::Ic0StableMemory.write(0, &b);
         assert_eq!(&icfs::StableMemory::bytes()[..], b);
 
         stable_memory.seek(SeekFrom::Start(0)).unwrap();
@@ -52,12 +57,13 @@ fn test_writer_vectored() {
     setup();
     STABLE_MEMORY.with(|stable_memory| {
         let mut stable_memory = *stable_memory.borrow();
+        assert!(stable_memory.is_write_vectored());
         assert_eq!(stable_memory.stream_position().unwrap(), 0);
 
         stable_memory.write_all_vectored(&mut [IoSlice::new(&[0])]).unwrap();
         assert_eq!(stable_memory.stream_position().unwrap(), 1);
 
-        stable_memory.write_all_vectored(&mut [IoSlice::new(&mut [1, 2, 3]), IoSlice::new(&mut [4, 5, 6, 7]),]).unwrap();
+        assert_eq!(stable_memory.write_vectored(&[IoSlice::new(&[1, 2, 3]), IoSlice::new(&[]), IoSlice::new(&[4, 5, 6, 7])]).unwrap(), 7);
         assert_eq!(stable_memory.stream_position().unwrap(), 8);
 
         stable_memory.write_all_vectored(&mut []).unwrap();
@@ -66,8 +72,8 @@ fn test_writer_vectored() {
         stable_memory.write_all_vectored(&mut [IoSlice::new(&[8, 9])]).unwrap();
         stable_memory.write_all_vectored(&mut [IoSlice::new(&[10])]).unwrap();
 
-        let b: &[_] = &[0, 1, 2, 3, 4, 5, 6, 7, 8];
-        assert_eq!(&icfs::StableMemory::bytes()[0..9], b);
+        let b: &[_] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(&icfs::StableMemory::bytes()[0..11], b);
     })
 }
 
@@ -93,16 +99,16 @@ fn test_writer_seek() {
 
         let capacity = icfs::StableMemory::capacity();
 
-        assert_eq!(stable_memory.seek(SeekFrom::End(-1)).unwrap(), capacity as u64 - 1);
-        assert_eq!(stable_memory.stream_position().unwrap(), capacity as u64 - 1);
+        assert_eq!(stable_memory.seek(SeekFrom::End(-1)).unwrap(), capacity - 1);
+        assert_eq!(stable_memory.stream_position().unwrap(), capacity - 1);
         assert_eq!(stable_memory.write(&[4]).unwrap(), 1);
-        assert_eq!(stable_memory.stream_position().unwrap(), capacity as u64);
+        assert_eq!(stable_memory.stream_position().unwrap(), capacity);
 
         let b: &[_] = &[1, 3, 2, 0, 0, 0, 0, 0];
         assert_eq!(&icfs::StableMemory::bytes()[0..8], b);
 
         let b: &[_] = &[0, 0, 0, 0, 0, 0, 0, 4];
-        assert_eq!(&icfs::StableMemory::bytes()[(capacity - 8)..], b);
+        assert_eq!(&icfs::StableMemory::bytes()[(capacity as usize - 8)..], b);
     })
 }
 
@@ -110,9 +116,11 @@ fn test_writer_seek() {
 fn test_writer_error() {
     setup();
     STABLE_MEMORY.with(|stable_memory| {
-        let mut stable_memory = *stable_memory.borrow();
+        let mut stable_memory = stable_memory
+            .borrow()
+            .with_max_pages(icfs::StableMemory::size());
         let capacity = icfs::StableMemory::capacity();
-        let offset = capacity as u64 - 2;
+        let offset = capacity - 2;
         assert_eq!(stable_memory.seek(SeekFrom::End(-2)).unwrap(), offset);
         assert_eq!(stable_memory.write(&[0]).unwrap(), 1);
         assert_eq!(stable_memory.write(&[0, 0]).unwrap(), 1);
@@ -201,12 +209,13 @@ fn test_reader_vectored() {
 
         let mut buf1 = [0; 4];
         let mut buf2 = [0; 4];
-        read_all_vectored(&mut stable_memory, &mut [IoSliceMut::new(&mut buf1), IoSliceMut::new(&mut buf2),]).unwrap();
+        assert_eq!(stable_memory.read_vectored(&mut [IoSliceMut::new(&mut buf1), IoSliceMut::new(&mut buf2),]).unwrap(), 8);
+        assert_eq!(stable_memory.stream_position().unwrap(), 9);
 
         let b1: &[_] = &[1, 2, 3, 4];
-        let b2: &[_] = &[5, 6, 7];
+        let b2: &[_] = &[5, 6, 7, 0];
         assert_eq!(buf1, b1);
-        assert_eq!(&buf2[..3], b2);
+        assert_eq!(buf2, b2);
 
         assert_eq!(stable_memory.read(&mut buf).unwrap(), 1);
     })
@@ -254,7 +263,7 @@ fn test_reader_error() {
     STABLE_MEMORY.with(|stable_memory| {
         let mut stable_memory = *stable_memory.borrow();
         let capacity = icfs::StableMemory::capacity();
-        let offset = capacity as u64 - 2;
+        let offset = capacity - 2;
         assert_eq!(stable_memory.seek(SeekFrom::End(-2)).unwrap(), offset);
 
         let mut buf = [0];
@@ -272,9 +281,11 @@ fn test_reader_error() {
 fn test_seek_past_end() {
     setup();
     STABLE_MEMORY.with(|stable_memory| {
-        let mut stable_memory = *stable_memory.borrow();
+        let mut stable_memory = stable_memory
+            .borrow()
+            .with_max_pages(icfs::StableMemory::size());
         let capacity = icfs::StableMemory::capacity();
-        let offset = capacity as u64 + 1;
+        let offset = capacity + 1;
 
         assert_eq!(stable_memory.seek(SeekFrom::Start(offset)).unwrap(), offset);
         assert!(stable_memory.read(&mut [0]).is_err());
@@ -298,3 +309,1108 @@ fn test_seek_before_0() {
         assert!(stable_memory.seek(SeekFrom::End(offset)).is_err());
     })
 }
+
+#[update]
+fn test_vec_memory() {
+    let mut memory = icfs::StableMemory::new(icfs::VecMemory::new());
+    assert_eq!(memory.memory().size(), 0);
+
+    assert_eq!(memory.write(&[0, 1, 2, 3]).unwrap(), 4);
+    assert_eq!(memory.memory().size(), 1);
+    assert_eq!(memory.stream_position().unwrap(), 4);
+
+    memory.seek(SeekFrom::Start(1)).unwrap();
+    let mut buf = [0; 2];
+    memory.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2]);
+
+    assert_eq!(memory.seek(SeekFrom::End(0)).unwrap(), 64 * 1024);
+    assert_eq!(memory.read(&mut buf).unwrap(), 0);
+}
+
+#[update]
+fn test_region() {
+    let memory = icfs::VecMemory::new();
+    let mut first = icfs::Region::new(memory.clone(), 0, 4);
+    let mut second = icfs::Region::new(memory.clone(), 4, 4);
+
+    assert_eq!(second.write(&[5, 6, 7, 8]).unwrap(), 4);
+    assert_eq!(first.write(&[1, 2, 3]).unwrap(), 3);
+    assert_eq!(first.write(&[4, 4, 4]).unwrap(), 1);
+    assert_eq!(first.write(&[4]).unwrap(), 0);
+
+    let mut buf = [0; 8];
+    icfs::StableMemory::new(memory).read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    assert_eq!(second.seek(SeekFrom::End(-3)).unwrap(), 1);
+    let mut buf = [0; 4];
+    assert_eq!(second.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf, [6, 7, 8, 0]);
+    assert_eq!(second.read(&mut buf).unwrap(), 0);
+
+    assert_eq!(second.seek(SeekFrom::Start(5)).unwrap(), 5);
+    assert!(second.read(&mut buf).is_err());
+    assert!(second.write(&[0]).is_err());
+    assert!(second.seek(SeekFrom::End(-5)).is_err());
+}
+
+#[update]
+fn test_memory_manager() {
+    let memory = icfs::VecMemory::new();
+    let memory_manager = icfs::MemoryManager::init_with_bucket_size(memory.clone(), 1);
+    let mut a = icfs::StableMemory::new(memory_manager.get(icfs::MemoryId::new(0)));
+    let mut b = icfs::StableMemory::new(memory_manager.get(icfs::MemoryId::new(1)));
+
+    // Interleave the buckets of both memories, then write across a bucket boundary.
+    assert_eq!(a.write(&[1; 4]).unwrap(), 4);
+    assert_eq!(b.write(&[2; 4]).unwrap(), 4);
+    assert_eq!(a.memory().grow(1).unwrap(), 1);
+    a.seek(SeekFrom::Start(64 * 1024 - 2)).unwrap();
+    assert_eq!(a.write(&[3, 4, 5, 6]).unwrap(), 4);
+
+    assert_eq!(a.memory().size(), 2);
+    assert_eq!(b.memory().size(), 1);
+    assert_eq!(memory.size(), 4);
+
+    // The layout is read back from the header, as it would be after an upgrade.
+    let memory_manager = icfs::MemoryManager::init(memory);
+    let mut a = icfs::StableMemory::new(memory_manager.get(icfs::MemoryId::new(0)));
+    let mut b = icfs::StableMemory::new(memory_manager.get(icfs::MemoryId::new(1)));
+    assert_eq!(a.memory().size(), 2);
+
+    let mut buf = [0; 4];
+    a.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1; 4]);
+    a.seek(SeekFrom::Start(64 * 1024 - 2)).unwrap();
+    a.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [3, 4, 5, 6]);
+    b.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [2; 4]);
+}
+
+#[update]
+fn test_read_at_write_at() {
+    setup();
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = stable_memory.borrow_mut();
+        assert_eq!(stable_memory.write_at(2, &[1, 2, 3]).unwrap(), 3);
+        stable_memory.write_all_at(0, &[4, 5]).unwrap();
+        assert_eq!(stable_memory.stream_position().unwrap(), 0);
+
+        let mut buf = [0; 5];
+        assert_eq!(stable_memory.read_at(0, &mut buf).unwrap(), 5);
+        assert_eq!(buf, [4, 5, 1, 2, 3]);
+
+        let mut buf = [0; 2];
+        stable_memory.read_exact_at(3, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert_eq!(stable_memory.stream_position().unwrap(), 0);
+
+        let capacity = icfs::StableMemory::capacity();
+        assert_eq!(stable_memory.read_at(capacity - 1, &mut buf).unwrap(), 1);
+        assert!(stable_memory.read_exact_at(capacity - 1, &mut buf).is_err());
+        assert!(stable_memory.read_at(capacity + 1, &mut buf).is_err());
+    })
+}
+
+#[update]
+fn test_error_kinds() {
+    setup();
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = *stable_memory.borrow();
+
+        let error = stable_memory.seek(SeekFrom::Current(-1)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+            Some(&icfs::Error::SeekBeforeStart)
+        );
+
+        let capacity = icfs::StableMemory::capacity();
+        stable_memory.seek(SeekFrom::Start(capacity + 1)).unwrap();
+        let error = stable_memory.read(&mut [0]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+            Some(&icfs::Error::OutOfBounds)
+        );
+
+        let error = stable_memory
+            .seek(SeekFrom::Start(u64::MAX))
+            .and_then(|_| stable_memory.seek(SeekFrom::Current(1)))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+            Some(&icfs::Error::OffsetOverflow)
+        );
+
+        let error = stable_memory.write_at(u64::MAX, &[0]).unwrap_err();
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+            Some(&icfs::Error::OffsetOverflow)
+        );
+
+        // Offsets past 4GiB are kept intact, even on wasm32.
+        assert_eq!(stable_memory.seek(SeekFrom::Start(1 << 33)).unwrap(), 1 << 33);
+        assert_eq!(stable_memory.stream_position().unwrap(), 1 << 33);
+    })
+}
+
+#[update]
+fn test_growth_policy() {
+    let mut memory = icfs::StableMemory::new(icfs::VecMemory::new());
+    memory.seek(SeekFrom::Start(64 * 1024 - 1)).unwrap();
+    assert_eq!(memory.write(&[0, 0]).unwrap(), 2);
+    assert_eq!(memory.memory().size(), 2);
+
+    let mut memory = memory.with_growth_policy(icfs::GrowthPolicy::Chunk(4));
+    memory.write_all(&[0; 64 * 1024]).unwrap();
+    assert_eq!(memory.memory().size(), 6);
+
+    let mut memory = memory.with_growth_policy(icfs::GrowthPolicy::Geometric);
+    memory.seek(SeekFrom::Start(6 * 64 * 1024)).unwrap();
+    memory.write_all(&[0]).unwrap();
+    assert_eq!(memory.memory().size(), 12);
+
+    let mut memory = memory.with_max_pages(13);
+    memory.seek(SeekFrom::Start(13 * 64 * 1024 - 1)).unwrap();
+    assert_eq!(memory.write(&[0, 0]).unwrap(), 1);
+    assert_eq!(memory.write(&[0]).unwrap(), 0);
+    assert_eq!(memory.memory().size(), 13);
+}
+
+#[update]
+fn test_read_range() {
+    setup();
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = *stable_memory.borrow();
+        stable_memory.write(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        assert_eq!(stable_memory.read_range(2, 3).unwrap(), [2, 3, 4]);
+        assert_eq!(stable_memory.read_range(8, 0).unwrap(), []);
+
+        let capacity = icfs::StableMemory::capacity();
+        assert_eq!(stable_memory.read_range(capacity - 1, 1).unwrap(), [0]);
+        assert_eq!(stable_memory.read_range(capacity - 1, 2), Err(icfs::Error::OutOfBounds));
+        assert_eq!(stable_memory.read_range(1, u64::MAX), Err(icfs::Error::OffsetOverflow));
+    })
+}
+
+#[update]
+fn test_chunks() {
+    setup();
+    STABLE_MEMORY.with(|stable_memory| {
+        let mut stable_memory = *stable_memory.borrow();
+        stable_memory.write(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        let chunks: Vec<_> = stable_memory.chunks_to(7, 3).collect();
+        assert_eq!(chunks, [vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+
+        let chunks = stable_memory.chunks(1024);
+        assert_eq!(chunks.size_hint().0, icfs::StableMemory::capacity() as usize / 1024);
+        assert_eq!(chunks.flatten().collect::<Vec<_>>(), icfs::StableMemory::bytes());
+
+        assert_eq!(stable_memory.chunks_to(u64::MAX, 1024).count(), icfs::StableMemory::capacity() as usize / 1024);
+    })
+}
+
+#[update]
+fn test_logical_len() {
+    let memory = icfs::VecMemory::new();
+    let mut stable_memory = icfs::StableMemory::new(memory.clone()).with_logical_len();
+    assert!(stable_memory.is_empty());
+
+    stable_memory.write(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    assert_eq!(stable_memory.len(), 8);
+    assert_eq!(stable_memory.seek(SeekFrom::End(0)).unwrap(), 8);
+
+    let mut buf = vec![];
+    stable_memory.rewind().unwrap();
+    assert_eq!(stable_memory.read_to_end(&mut buf).unwrap(), 8);
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(stable_memory.read_at(100, &mut [0; 4]).unwrap(), 0);
+
+    stable_memory.set_len(4).unwrap();
+    stable_memory.set_len(6).unwrap();
+    assert_eq!(stable_memory.read_range(0, 6).unwrap(), [1, 2, 3, 4, 0, 0]);
+    assert!(stable_memory.read_range(0, 7).is_err());
+
+    let stable_memory = icfs::StableMemory::new(memory).with_logical_len();
+    assert_eq!(stable_memory.len(), 6);
+    assert_eq!(stable_memory.chunks(4).collect::<Vec<_>>(), [vec![1, 2, 3, 4], vec![0, 0]]);
+}
+
+#[update]
+fn test_block_cache() {
+    let memory = icfs::VecMemory::new();
+    memory.grow(1).unwrap();
+    let cache = icfs::BlockCache::new(memory.clone(), 16, 2);
+
+    cache.write(8, &[1; 16]);
+    assert_eq!(cache.dirty_blocks(), 2);
+    assert_eq!(icfs::StableMemory::new(memory.clone()).read_range(0, 32).unwrap(), [0; 32]);
+
+    let mut buf = [0; 4];
+    cache.read(22, &mut buf);
+    assert_eq!(buf, [1, 1, 0, 0]);
+
+    // Evicts the least recently used block, writing it back.
+    cache.read(32, &mut buf);
+    assert_eq!(cache.dirty_blocks(), 1);
+    assert_eq!(icfs::StableMemory::new(memory.clone()).read_range(0, 16).unwrap()[8..], [1; 8]);
+
+    let mut stable_memory = icfs::StableMemory::new(cache.clone());
+    stable_memory.seek(SeekFrom::Start(100)).unwrap();
+    stable_memory.write_all(&[2; 4]).unwrap();
+    stable_memory.flush().unwrap();
+    assert_eq!(cache.dirty_blocks(), 0);
+    assert_eq!(icfs::StableMemory::new(memory.clone()).read_range(0, 104).unwrap()[8..], {
+        let mut expected = [0; 96];
+        expected[..16].copy_from_slice(&[1; 16]);
+        expected[92..].copy_from_slice(&[2; 4]);
+        expected
+    });
+
+    cache.write(200, &[3]);
+    drop(stable_memory);
+    drop(cache);
+    assert_eq!(icfs::StableMemory::new(memory).read_range(200, 1).unwrap(), [3]);
+}
+
+#[update]
+fn test_journal() {
+    let data = icfs::VecMemory::new();
+    let journal_memory = icfs::VecMemory::new();
+    let stable_memory = icfs::StableMemory::new(data.clone());
+    let journal = icfs::Journal::init(stable_memory.clone(), journal_memory.clone()).unwrap();
+
+    assert_eq!(journal.write_all_at(0, &[1]), Err(icfs::Error::NoTransaction));
+    journal.begin().unwrap();
+    assert_eq!(journal.begin(), Err(icfs::Error::TransactionInProgress));
+    journal.write_all_at(0, &[1, 2, 3, 4]).unwrap();
+    journal.write_all_at(2, &[5]).unwrap();
+
+    let mut buf = [0; 4];
+    assert_eq!(journal.read_at(0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [1, 2, 5, 4]);
+    assert_eq!(data.size(), 0);
+
+    // Loading the journal again (as after an upgrade) discards the transaction.
+    let journal = icfs::Journal::init(stable_memory.clone(), journal_memory.clone()).unwrap();
+    assert!(!journal.in_transaction());
+    assert_eq!(journal.read_at(0, &mut buf).unwrap(), 0);
+    assert_eq!(buf, [0; 4]);
+
+    journal.begin().unwrap();
+    journal.write_all_at(0, &[1, 2, 3, 4]).unwrap();
+    journal.write_all_at(2, &[5]).unwrap();
+    journal.commit().unwrap();
+    assert_eq!(stable_memory.read_range(0, 4).unwrap(), [1, 2, 5, 4]);
+
+    journal.begin().unwrap();
+    journal.write_all_at(0, &[0]).unwrap();
+    journal.abort().unwrap();
+    assert_eq!(stable_memory.read_range(0, 1).unwrap(), [1]);
+
+    // A committed transaction that could not be fully applied is applied
+    // again when the journal is loaded.
+    let limited = stable_memory.clone().with_max_pages(1);
+    let journal = icfs::Journal::init(limited, journal_memory.clone()).unwrap();
+    journal.begin().unwrap();
+    journal.write_all_at(0, &[9]).unwrap();
+    journal.write_all_at(1 << 16, &[9]).unwrap();
+    assert_eq!(journal.commit(), Err(icfs::Error::OutOfBounds));
+    assert_eq!(journal.begin(), Err(icfs::Error::TransactionInProgress));
+
+    icfs::Journal::init(stable_memory.clone(), journal_memory).unwrap();
+    assert_eq!(stable_memory.read_range(0, 1).unwrap(), [9]);
+    assert_eq!(stable_memory.read_range(1 << 16, 1).unwrap(), [9]);
+}
+
+#[update]
+fn test_checksummed_region() {
+    let memory = icfs::VecMemory::new();
+    let mut region = icfs::ChecksummedRegion::new(memory.clone(), 0, 16, 4);
+    assert_eq!(region.len(), 64);
+    assert_eq!(region.end(), 80);
+    region.verify().unwrap();
+
+    let mut buf = [1; 8];
+    assert_eq!(region.read_at(12, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [0; 8]);
+
+    region.write_all(&[1; 20]).unwrap();
+    region.write_all_at(30, &[2; 4]).unwrap();
+    region.verify().unwrap();
+    assert_eq!(region.read_at(16, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [1, 1, 1, 1, 0, 0, 0, 0]);
+
+    // Scribbling over block 1 (after the 16 byte checksum table) is detected.
+    memory.write(16 + 16 + 1, &[0xff]);
+    let error = region.read_at(16, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::Corrupted { block: 1 })
+    );
+    assert_eq!(region.verify(), Err(icfs::Error::Corrupted { block: 1 }));
+    assert!(region.write_at(17, &[0]).is_err());
+    assert_eq!(region.read_at(0, &mut buf).unwrap(), 8);
+
+    // Overwriting the whole block replaces its checksum.
+    region.write_all_at(16, &[3; 16]).unwrap();
+    region.verify().unwrap();
+}
+
+#[update]
+fn test_encrypted_memory() {
+    let key = [7; 32];
+    let memory = icfs::VecMemory::new();
+    let mut encrypted_memory = icfs::EncryptedMemory::new(memory.clone(), 0, 16, 4, &key, 0);
+    assert_eq!(encrypted_memory.len(), 64);
+    // The high-water mark and the counters and tags of the 4 sectors need 120
+    // bytes, i.e. 8 sectors.
+    assert_eq!(encrypted_memory.end(), 128 + 64);
+
+    let mut buf = [1; 8];
+    assert_eq!(encrypted_memory.read_at(12, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [0; 8]);
+
+    encrypted_memory.write_all(&[1; 20]).unwrap();
+    encrypted_memory.seek(SeekFrom::Start(16)).unwrap();
+    encrypted_memory.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 0, 0, 0, 0]);
+
+    // The plaintext never reaches the memory.
+    let ciphertext = icfs::StableMemory::new(memory.clone()).read_range(128, 16).unwrap();
+    assert_ne!(ciphertext, [1; 16]);
+
+    // Rewriting the same plaintext gives different ciphertext.
+    encrypted_memory.write_all_at(0, &[1; 16]).unwrap();
+    assert_ne!(icfs::StableMemory::new(memory.clone()).read_range(128, 16).unwrap(), ciphertext);
+
+    // The wrong key, or tampering, is detected.
+    let wrong_key = icfs::EncryptedMemory::new(memory.clone(), 0, 16, 4, &[8; 32], 0);
+    let error = wrong_key.read_at(0, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::DecryptionFailed { sector: 0 })
+    );
+    memory.write(128 + 16, &[0xff]);
+    assert!(encrypted_memory.read_at(16, &mut buf).is_err());
+    assert_eq!(encrypted_memory.read_at(0, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [1; 8]);
+
+    // Regions that share a key, even in different memories, never share
+    // nonces, so the same data in the same sector encrypts differently.
+    let first_memory = icfs::VecMemory::new();
+    let second_memory = icfs::VecMemory::new();
+    let first = icfs::EncryptedMemory::new(first_memory.clone(), 0, 16, 4, &key, 1);
+    let second = icfs::EncryptedMemory::new(second_memory.clone(), 0, 16, 4, &key, 2);
+    first.write_all_at(0, &[1; 16]).unwrap();
+    second.write_all_at(0, &[1; 16]).unwrap();
+    assert_ne!(
+        icfs::StableMemory::new(first_memory).read_range(128, 16).unwrap(),
+        icfs::StableMemory::new(second_memory.clone()).read_range(128, 16).unwrap()
+    );
+    // A region opened with another region's id cannot read its sectors.
+    let mismatched = icfs::EncryptedMemory::new(second_memory, 0, 16, 4, &key, 1);
+    assert!(mismatched.read_at(0, &mut buf).is_err());
+
+    // The sectors skipped over by a write read as zeros, but a written sector
+    // cannot be passed off as never written by zeroing its metadata.
+    let memory = icfs::VecMemory::new();
+    let encrypted_memory = icfs::EncryptedMemory::new(memory.clone(), 0, 16, 4, &key, 3);
+    encrypted_memory.write_all_at(32, &[1; 16]).unwrap();
+    encrypted_memory.read_exact_at(16, &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+    encrypted_memory.read_exact_at(32, &mut buf).unwrap();
+    assert_eq!(buf, [1; 8]);
+    // The metadata of sector 1 follows the mark and that of sector 0.
+    memory.write(2 * 24, &[0; 24]);
+    let error = encrypted_memory.read_at(16, &mut buf).unwrap_err();
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::DecryptionFailed { sector: 1 })
+    );
+    // Nor can the mark be lowered.
+    memory.write(0, &[1]);
+    let error = encrypted_memory.read_at(48, &mut buf).unwrap_err();
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::DecryptionFailed { sector: 3 })
+    );
+}
+
+#[update]
+fn test_compressed_memory() {
+    let memory = icfs::VecMemory::new();
+    let mut compressed_memory = icfs::CompressedMemory::new(memory.clone(), 0, 512, 4);
+    assert_eq!(compressed_memory.len(), 2048);
+    // The table of extents alone takes 8 + 33 * 8 + 4 * 16 bytes.
+    assert_eq!(compressed_memory.physical_bytes(), 336);
+    assert_eq!(compressed_memory.stats().ratio(), 1.0);
+
+    let mut buf = [1; 8];
+    assert_eq!(compressed_memory.read_at(508, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [0; 8]);
+
+    // Repetitive data takes up a fraction of its size.
+    compressed_memory.write_all(&[1; 600]).unwrap();
+    let stats = compressed_memory.stats();
+    assert_eq!(stats.logical_bytes, 1024);
+    assert!(stats.ratio() > 4.0);
+    assert!(stats.physical_bytes < 336 + 256);
+    compressed_memory.seek(SeekFrom::Start(596)).unwrap();
+    compressed_memory.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 0, 0, 0, 0]);
+
+    // Data that does not compress is stored as it is, in a new extent.
+    let mut state = 1u32;
+    let noise: Vec<u8> = (0..512)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 24) as u8
+        })
+        .collect();
+    let physical_bytes = compressed_memory.physical_bytes();
+    compressed_memory.write_all_at(0, &noise).unwrap();
+    assert_eq!(compressed_memory.physical_bytes(), physical_bytes + 512);
+    let mut sector = vec![0; 512];
+    compressed_memory.read_exact_at(0, &mut sector).unwrap();
+    assert_eq!(sector, noise);
+
+    // Overwritten compressed bytes are detected.
+    let stats = compressed_memory.stats();
+    let extents_end = stats.physical_bytes - 336;
+    memory.write(336, &vec![0xff; (extents_end - 512) as usize]);
+    let error = compressed_memory.read_at(512, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::DecompressionFailed { sector: 1 })
+    );
+    assert_eq!(compressed_memory.read_at(0, &mut buf).unwrap(), 8);
+    assert_eq!(buf, noise[..8]);
+
+    // Sectors that keep changing size reuse the extents they leave, so the
+    // memory in use stops growing once there is an extent of each size to
+    // spare.
+    let compressed_memory = icfs::CompressedMemory::new(icfs::VecMemory::new(), 0, 512, 4);
+    let mut physical_bytes = vec![];
+    for round in 0..10 {
+        let (first, second) = if round % 2 == 0 {
+            (&noise[..], &[1; 512][..])
+        } else {
+            (&[1; 512][..], &noise[..])
+        };
+        compressed_memory.write_all_at(0, first).unwrap();
+        compressed_memory.write_all_at(512, second).unwrap();
+        physical_bytes.push(compressed_memory.stats().physical_bytes);
+    }
+    assert!(physical_bytes[3..].iter().all(|bytes| *bytes == physical_bytes[2]));
+    compressed_memory.read_exact_at(512, &mut sector).unwrap();
+    assert_eq!(sector, noise);
+}
+
+#[update]
+fn test_dirty_pages() {
+    let page_size = icfs::WASM_PAGE_SIZE_IN_BYTES;
+    let memory = icfs::VecMemory::new();
+    let dirty_pages = icfs::DirtyPages::new(&memory);
+    let mut stable_memory = icfs::StableMemory::new(memory.clone())
+        .with_logical_len()
+        .with_dirty_pages(dirty_pages);
+    assert!(dirty_pages.is_empty());
+    assert!(!dirty_pages.needs_full_resync());
+
+    // The logical length lives in page 0, and the write spans pages 1 and 2.
+    stable_memory.seek(SeekFrom::Start(page_size * 2 - 12)).unwrap();
+    stable_memory.write_all(&[1; 8]).unwrap();
+    assert_eq!(dirty_pages.pages(), [0, 1, 2]);
+
+    let backup = icfs::VecMemory::new();
+    let delta = dirty_pages.export_delta(&memory).unwrap();
+    assert_eq!(delta.len(), 3);
+    assert!(dirty_pages.is_empty());
+    icfs::DirtyPages::apply_delta(&backup, &delta).unwrap();
+    assert_eq!(icfs::StableMemory::new(backup.clone()).read_range(0, page_size * 3).unwrap(), icfs::StableMemory::new(memory.clone()).read_range(0, page_size * 3).unwrap());
+
+    // Only pages written since the last checkpoint are exported.
+    stable_memory.write_all_at(0, &[2; 4]).unwrap();
+    assert_eq!(dirty_pages.pages(), [0]);
+    icfs::DirtyPages::apply_delta(&backup, &dirty_pages.export_delta(&memory).unwrap()).unwrap();
+    let mut buf = [0; 8];
+    icfs::StableMemory::new(backup.clone()).with_logical_len().read_exact_at(page_size * 2 - 12, &mut buf).unwrap();
+    assert_eq!(buf, [1; 8]);
+    assert_eq!(icfs::StableMemory::new(backup.clone()).read_range(0, page_size * 3).unwrap(), icfs::StableMemory::new(memory.clone()).read_range(0, page_size * 3).unwrap());
+
+    // Truncating zeroes the bytes cut off, which are tracked too.
+    stable_memory.set_len(page_size).unwrap();
+    assert_eq!(dirty_pages.checkpoint(), [0, 1, 2]);
+
+    // The pages are lost on upgrade, so a tracker for a memory that already
+    // holds data asks for a full resync, once.
+    let dirty_pages = icfs::DirtyPages::new(&memory);
+    assert!(dirty_pages.needs_full_resync());
+    let stable_memory = icfs::StableMemory::new(memory.clone()).with_dirty_pages(dirty_pages);
+    stable_memory.write_all_at(page_size, &[3; 4]).unwrap();
+    assert_eq!(dirty_pages.export_delta(&memory), None);
+    assert!(!dirty_pages.needs_full_resync());
+    stable_memory.write_all_at(page_size, &[4; 4]).unwrap();
+    assert_eq!(dirty_pages.export_delta(&memory).unwrap().len(), 1);
+
+    // Deltas that do not fit are rejected.
+    let error = icfs::DirtyPages::apply_delta(&backup, &[(u64::MAX, vec![0])]).unwrap_err();
+    assert_eq!(error, icfs::Error::OffsetOverflow);
+}
+
+#[update]
+fn test_image() {
+    let source = icfs::VecMemory::new();
+    source.grow(2).unwrap();
+    source.write(100, b"hello");
+    source.write(icfs::WASM_PAGE_SIZE_IN_BYTES + 100, b"world");
+
+    // Each message exports and imports one chunk.
+    let mut export = icfs::ImageExport::new(source.clone());
+    let destination = icfs::VecMemory::new();
+    let mut import = icfs::ImageImport::new(destination.clone());
+    assert_eq!(export.total_len(), 2 * icfs::WASM_PAGE_SIZE_IN_BYTES);
+    let mut chunks = vec![];
+    while let Some(chunk) = export.next_chunk(50_000) {
+        assert_eq!(chunk.offset, import.offset());
+        import.import_chunk(&chunk).unwrap();
+        chunks.push(chunk);
+    }
+    assert_eq!(chunks.len(), 3);
+    assert!(export.is_done() && import.is_done());
+    let total_len = export.total_len();
+    assert_eq!(
+        icfs::StableMemory::new(destination).read_range(0, total_len).unwrap(),
+        icfs::StableMemory::new(source.clone()).read_range(0, total_len).unwrap()
+    );
+
+    // Chunks out of order, and chunks that were tampered with, are rejected
+    // without derailing the import.
+    let mut import = icfs::ImageImport::new(icfs::VecMemory::new());
+    assert_eq!(import.import_chunk(&chunks[1]), Err(icfs::Error::UnexpectedChunk { expected_offset: 0 }));
+    let mut tampered = chunks[0].clone();
+    tampered.bytes[100] = b'j';
+    assert_eq!(import.import_chunk(&tampered), Err(icfs::Error::HashMismatch { offset: 0 }));
+    for chunk in &chunks {
+        import.import_chunk(chunk).unwrap();
+    }
+    assert!(import.is_done());
+    assert_eq!(import.import_chunk(&chunks[2]), Err(icfs::Error::UnexpectedChunk { expected_offset: total_len }));
+
+    // The last chunk exported can be asked for again, e.g. if its reply was
+    // lost, but earlier ones cannot.
+    let mut export = icfs::ImageExport::new(source);
+    assert_eq!(export.export_chunk(0, 50_000).unwrap().as_ref(), Some(&chunks[0]));
+    assert_eq!(export.export_chunk(0, 50_000).unwrap().as_ref(), Some(&chunks[0]));
+    assert_eq!(export.export_chunk(50_000, 50_000).unwrap().as_ref(), Some(&chunks[1]));
+    assert_eq!(
+        export.export_chunk(0, 50_000),
+        Err(icfs::Error::UnexpectedChunk { expected_offset: 100_000 })
+    );
+    assert_eq!(export.export_chunk(100_000, 50_000).unwrap().as_ref(), Some(&chunks[2]));
+    assert_eq!(export.export_chunk(total_len, 50_000), Ok(None));
+    assert_eq!(export.export_chunk(100_000, 50_000).unwrap().as_ref(), Some(&chunks[2]));
+}
+
+#[update]
+fn test_snapshot_memory() {
+    let memory = icfs::FaultyMemory::new(icfs::VecMemory::new());
+    let snapshot_memory = icfs::SnapshotMemory::init(memory.clone());
+    let mut live = icfs::StableMemory::new(snapshot_memory.clone());
+    live.write_all(&[1; 8]).unwrap();
+    live.write_all_at(icfs::WASM_PAGE_SIZE_IN_BYTES, &[1; 8]).unwrap();
+
+    // Taking a snapshot reserves a page to copy each live page to, and fails
+    // if the memory cannot grow enough for them.
+    memory.fail_grows_after(1);
+    assert_eq!(
+        snapshot_memory.snapshot(),
+        Err(icfs::Error::GrowFailed { requested: 2, available: 4 + 2 })
+    );
+    assert!(snapshot_memory.snapshots().is_empty());
+    memory.heal();
+    let id = snapshot_memory.snapshot().unwrap();
+    assert_eq!(snapshot_memory.snapshots(), [id]);
+    assert_eq!(snapshot_memory.num_free_pages(), 2);
+    assert_eq!(memory.size(), 4 + 2 + 2);
+    let snapshot = icfs::StableMemory::new(snapshot_memory.get(id).unwrap());
+
+    // Live writes are not seen through the snapshot, and only copy the pages
+    // written to, without growing the memory to do so.
+    memory.fail_grows_after(1);
+    live.write_all_at(4, &[2; 8]).unwrap();
+    live.write_all_at(2 * icfs::WASM_PAGE_SIZE_IN_BYTES, &[2; 8]).unwrap();
+    memory.heal();
+    let mut buf = [0; 12];
+    live.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+    snapshot.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+    assert_eq!(snapshot.len(), 2 * icfs::WASM_PAGE_SIZE_IN_BYTES);
+    assert_eq!(memory.size(), 4 + 2 + 2 + 1);
+
+    // Releasing the snapshot frees the page the live memory moved away from,
+    // along with the page that was reserved for the other one.
+    assert!(snapshot_memory.release(id));
+    assert!(!snapshot_memory.release(id));
+    assert!(snapshot_memory.get(id).is_none());
+    assert_eq!(snapshot_memory.num_free_pages(), 2);
+    let id = snapshot_memory.snapshot().unwrap();
+    live.write_all_at(icfs::WASM_PAGE_SIZE_IN_BYTES, &[3; 8]).unwrap();
+    assert_eq!(snapshot_memory.num_free_pages(), 2);
+    assert_eq!(memory.size(), 4 + 3 + 3);
+
+    // The live memory survives being loaded again, while snapshots do not.
+    let snapshot_memory = icfs::SnapshotMemory::init(memory.clone());
+    assert!(snapshot_memory.get(id).is_none());
+    assert_eq!(snapshot_memory.num_free_pages(), 3);
+    let live = icfs::StableMemory::new(snapshot_memory);
+    live.read_exact_at(icfs::WASM_PAGE_SIZE_IN_BYTES, &mut buf).unwrap();
+    assert_eq!(buf, [3, 3, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0]);
+    live.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+}
+
+#[update]
+fn test_instrumented_memory() {
+    let memory = icfs::InstrumentedMemory::new(icfs::VecMemory::new());
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    stable_memory.write_all(&[1; 8]).unwrap();
+    stable_memory.write_all_at(4, &[2; 8]).unwrap();
+    let mut buf = [0; 12];
+    stable_memory.read_exact_at(0, &mut buf).unwrap();
+
+    let metrics = memory.metrics();
+    assert_eq!((metrics.reads.calls, metrics.reads.bytes), (1, 12));
+    assert_eq!((metrics.writes.calls, metrics.writes.bytes), (2, 16));
+    assert_eq!((metrics.grows.calls, metrics.grows.bytes), (1, icfs::WASM_PAGE_SIZE_IN_BYTES));
+
+    let text = metrics.to_prometheus("icfs_stable_memory");
+    assert!(text.contains("# TYPE icfs_stable_memory_calls_total counter\n"));
+    assert!(text.contains("icfs_stable_memory_calls_total{operation=\"write\"} 2\n"));
+    assert!(text.contains("icfs_stable_memory_bytes_total{operation=\"read\"} 12\n"));
+
+    memory.reset();
+    assert_eq!(memory.metrics(), icfs::IoMetrics::default());
+}
+
+#[update]
+fn test_faulty_memory() {
+    let icfs_error = |error: std::io::Error| error.get_ref().unwrap().downcast_ref::<icfs::Error>().copied();
+
+    // Growing fails once the budget of pages is spent.
+    let memory = icfs::FaultyMemory::new(icfs::VecMemory::new());
+    memory.fail_grows_after(1);
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    stable_memory.write_all(&[1; 8]).unwrap();
+    let error = stable_memory.write_all_at(icfs::WASM_PAGE_SIZE_IN_BYTES, &[1; 8]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+    memory.heal();
+    stable_memory.write_all_at(icfs::WASM_PAGE_SIZE_IN_BYTES, &[1; 8]).unwrap();
+
+    // The checksum table of 4 blocks takes up one block of its own.
+    let memory = icfs::FaultyMemory::new(icfs::VecMemory::new());
+    let mut region = icfs::ChecksummedRegion::new(memory.clone(), 0, 512, 4);
+    region.write_all(&[1; 2048]).unwrap();
+    let mut buf = [0; 512];
+
+    // Bits flipped on the way back are caught.
+    memory.flip_bits_on_read(512 + 5, 0b100);
+    let error = region.read_exact_at(0, &mut buf).unwrap_err();
+    assert_eq!(icfs_error(error), Some(icfs::Error::Corrupted { block: 0 }));
+    memory.heal();
+    region.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 512]);
+
+    // So is a write torn halfway through a block.
+    memory.tear_next_write(512);
+    region.write_all_at(512, &[2; 512]).unwrap();
+    let error = region.read_exact_at(512, &mut buf).unwrap_err();
+    assert_eq!(icfs_error(error), Some(icfs::Error::Corrupted { block: 1 }));
+    let mut torn = [0; 512];
+    memory.memory().read(1024, &mut torn);
+    assert_eq!(torn[..256], [2; 256]);
+    assert_eq!(torn[256..], [1; 256]);
+
+    // And a write cut short.
+    memory.cut_writes_at(1536 + 100);
+    region.write_all_at(1024, &[3; 512]).unwrap();
+    let error = region.read_exact_at(1024, &mut buf).unwrap_err();
+    assert_eq!(icfs_error(error), Some(icfs::Error::Corrupted { block: 2 }));
+    region.read_exact_at(1536, &mut buf).unwrap();
+
+    // A file system on top sees the faults as errors, rather than panicking
+    // or reading garbage.
+    let memory = icfs::FaultyMemory::new(icfs::VecMemory::new());
+    let volume = icfs::ChecksummedRegion::new(memory.clone(), 0, 512, 2048);
+    memory.fail_grows_after(0);
+    let format = fatfs::format_volume(
+        &mut fatfs::StdIoWrapper::from(volume.clone()),
+        fatfs::FormatVolumeOptions::new(),
+    );
+    assert!(format.is_err());
+    memory.heal();
+    fatfs::format_volume(
+        &mut fatfs::StdIoWrapper::from(volume.clone()),
+        fatfs::FormatVolumeOptions::new(),
+    )
+    .unwrap();
+
+    let options = fatfs::FsOptions::new().time_provider(fatfs::NullTimeProvider::new());
+    let fs = fatfs::FileSystem::new(fatfs::StdIoWrapper::from(volume), options).unwrap();
+    let mut file = fs.root_dir().create_file("torn.txt").unwrap();
+    file.write_all(&[1; 1024]).unwrap();
+    file.flush().unwrap();
+
+    // Overwriting the file tears its first sector.
+    memory.tear_next_write(512);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(&[2; 1024]).unwrap();
+    file.flush().unwrap();
+    let mut contents = vec![];
+    let mut file = fs.root_dir().open_file("torn.txt").unwrap();
+    assert!(file.read_to_end(&mut contents).is_err());
+}
+
+#[update]
+fn test_superblock() {
+    // Empty memory holds no superblock, and neither does memory laid out some
+    // other way.
+    let memory = icfs::VecMemory::new();
+    assert!(icfs::Superblock::load(memory.clone()).is_none());
+    let other = icfs::VecMemory::new();
+    other.grow(1).unwrap();
+    other.write(0, b"FAT");
+    assert!(icfs::Superblock::load(other).is_none());
+
+    // Regions are laid out one after the other, after the superblock.
+    let mut superblock = icfs::Superblock::init(memory.clone(), 1).unwrap();
+    assert_eq!(superblock.version(), 1);
+    assert!(superblock.regions().is_empty());
+    let mut data = superblock.add_region("data", 100).unwrap();
+    let index = superblock.add_region("index", 10).unwrap();
+    assert_eq!(data.base(), icfs::SUPERBLOCK_SIZE_IN_BYTES);
+    assert_eq!(index.base(), icfs::SUPERBLOCK_SIZE_IN_BYTES + 100);
+    data.write_all(&[1; 100]).unwrap();
+    assert!(data.write_all(&[1]).is_err());
+
+    // They are found again once the superblock is loaded.
+    let superblock = icfs::Superblock::init(memory.clone(), 2).unwrap();
+    assert_eq!(superblock.version(), 1);
+    let names: Vec<_> = superblock.regions().into_iter().map(|(name, _)| name.to_string()).collect();
+    assert_eq!(names, ["data", "index"]);
+    let data = superblock.region("data").unwrap();
+    assert_eq!(data.len(), 100);
+    let mut buf = [0; 100];
+    data.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 100]);
+    assert!(superblock.region("log").is_none());
+
+    // Migrations run in order from the version stored, and the version is
+    // bumped after each of them.
+    let migrations = icfs::Migrations::<icfs::VecMemory>::new()
+        .register(1, |superblock| superblock.add_region("log", 50).map(|_| ()))
+        .register(2, |superblock| {
+            let data = superblock.region("data").unwrap();
+            superblock.memory().write(data.base(), &[2; 100]);
+            Ok(())
+        })
+        .register(3, |_| Err(icfs::Error::OutOfBounds));
+    assert_eq!(migrations.latest_version(), Some(4));
+    let mut superblock = icfs::Superblock::load(memory.clone()).unwrap();
+    assert_eq!(superblock.migrate(&migrations), Err(icfs::Error::OutOfBounds));
+    assert_eq!(superblock.version(), 3);
+
+    let mut superblock = icfs::Superblock::load(memory).unwrap();
+    assert_eq!(superblock.version(), 3);
+    assert_eq!(superblock.region("log").unwrap().base(), icfs::SUPERBLOCK_SIZE_IN_BYTES + 110);
+    superblock.region("data").unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [2; 100]);
+    let migrations = icfs::Migrations::new().register(3, |_| Ok(()));
+    assert_eq!(superblock.migrate(&migrations), Ok(4));
+    assert_eq!(superblock.migrate(&migrations), Ok(4));
+}
+
+#[update]
+fn test_stable_save() {
+    let mut state = std::collections::BTreeMap::new();
+    state.insert("files".to_string(), (0..10_000).collect::<Vec<u64>>());
+    state.insert("dirs".to_string(), vec![1, 2, 3]);
+
+    // Values saved one after the other are restored in the same order.
+    let memory = icfs::VecMemory::new();
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    icfs::stable_save(&mut stable_memory, 2, &state).unwrap();
+    icfs::stable_save(&mut stable_memory, 1, &"next").unwrap();
+    let end = stable_memory.stream_position().unwrap();
+
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    let (schema_version, restored): (u32, std::collections::BTreeMap<String, Vec<u64>>) =
+        icfs::stable_restore(&mut stable_memory).unwrap();
+    assert_eq!(schema_version, 2);
+    assert_eq!(restored, state);
+    let (schema_version, next): (u32, String) = icfs::stable_restore(&mut stable_memory).unwrap();
+    assert_eq!((schema_version, next.as_str()), (1, "next"));
+    assert_eq!(stable_memory.stream_position().unwrap(), end);
+
+    // Values can also be streamed through by hand, in any encoding.
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    let mut writer = icfs::UpgradeWriter::new(&mut stable_memory, 7).unwrap();
+    writer.write_all(b"raw bytes").unwrap();
+    assert_eq!(writer.finish().unwrap(), 9);
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    let mut reader = icfs::UpgradeReader::new(&mut stable_memory).unwrap();
+    assert_eq!((reader.schema_version(), reader.len()), (7, 9));
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"raw bytes");
+
+    // A value that does not decode as the type asked for is an error.
+    let mut stable_memory = icfs::StableMemory::new(memory);
+    let error = icfs::stable_restore::<_, Vec<u64>>(&mut stable_memory).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    let mut stable_memory = icfs::StableMemory::new(icfs::VecMemory::new());
+    let mut writer = icfs::UpgradeWriter::new(&mut stable_memory, 1).unwrap();
+    writer.serialize(&1u64).unwrap();
+    writer.serialize(&2u64).unwrap();
+    writer.finish().unwrap();
+    stable_memory.seek(SeekFrom::Start(0)).unwrap();
+    let error = icfs::stable_restore::<_, u64>(&mut stable_memory).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // So is a value whose saving did not finish, or none at all.
+    let memory = icfs::VecMemory::new();
+    let mut stable_memory = icfs::StableMemory::new(memory.clone());
+    let mut writer = icfs::UpgradeWriter::new(&mut stable_memory, 1).unwrap();
+    writer.serialize(&state).unwrap();
+    drop(writer);
+    let mut stable_memory = icfs::StableMemory::new(memory);
+    let error = icfs::stable_restore::<_, u64>(&mut stable_memory).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    let mut stable_memory = icfs::StableMemory::new(icfs::VecMemory::new());
+    let error = icfs::stable_restore::<_, u64>(&mut stable_memory).unwrap_err();
+    assert_eq!(
+        error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+        Some(&icfs::Error::NoSavedValue { offset: 0 })
+    );
+}
+
+#[update]
+fn test_allocator() {
+    let memory = icfs::VecMemory::new();
+    let allocator = icfs::Allocator::init(icfs::StableMemory::new(memory.clone())).unwrap();
+    let a = allocator.alloc(10).unwrap();
+    let b = allocator.alloc(100).unwrap();
+    let c = allocator.alloc(10).unwrap();
+    assert!(a < b && b < c);
+    assert_eq!(allocator.capacity(a).unwrap(), 16);
+    assert_eq!(allocator.capacity(b).unwrap(), 112);
+    allocator.stable_memory().write_all_at(b, &[1; 100]).unwrap();
+
+    // Freed space is reused, and free neighbours are merged.
+    allocator.free(a).unwrap();
+    assert_eq!(allocator.alloc(16).unwrap(), a);
+    allocator.free(a).unwrap();
+    allocator.free(b).unwrap();
+    assert_eq!(allocator.alloc(144).unwrap(), a);
+    assert_eq!(allocator.capacity(a).unwrap(), 144);
+
+    // Freeing the last block gives its space back to the heap.
+    let heap_end = allocator.heap_end().unwrap();
+    allocator.free(c).unwrap();
+    assert_eq!(allocator.heap_end().unwrap(), c - 8);
+    assert_eq!(allocator.alloc(10).unwrap(), c);
+    assert_eq!(allocator.heap_end().unwrap(), heap_end);
+
+    // Objects keep their bytes when they are resized.
+    allocator.stable_memory().write_all_at(a, &[2; 144]).unwrap();
+    let a = allocator.realloc(a, 1000).unwrap();
+    let mut buf = [0; 144];
+    allocator.stable_memory().read_exact_at(a, &mut buf).unwrap();
+    assert_eq!(buf, [2; 144]);
+    let d = allocator.alloc(10).unwrap();
+    assert!(d < c);
+    // The object at the end of the heap grows in place, and every object
+    // shrinks in place.
+    assert_eq!(allocator.realloc(a, 2000).unwrap(), a);
+    assert_eq!(allocator.realloc(a, 100).unwrap(), a);
+    assert_eq!(allocator.capacity(a).unwrap(), 112);
+    allocator.stable_memory().read_exact_at(a, &mut buf[..100]).unwrap();
+    assert_eq!(buf[..100], [2; 100]);
+
+    // The heap is found again as it was.
+    let heap_end = allocator.heap_end().unwrap();
+    let allocator = icfs::Allocator::init(icfs::StableMemory::new(memory)).unwrap();
+    assert_eq!(allocator.heap_end().unwrap(), heap_end);
+    assert_eq!(allocator.capacity(d).unwrap(), 16);
+    allocator.free(d).unwrap();
+    allocator.free(c).unwrap();
+    assert_eq!(allocator.alloc(48).unwrap(), d);
+}
+
+#[update]
+fn test_stable_vec() {
+    let memory = icfs::VecMemory::new();
+    let mut vec = icfs::StableVec::<u64, _>::init(memory.clone()).unwrap();
+    assert!(vec.is_empty());
+    assert_eq!(vec.pop(), None);
+    // Enough elements to grow the memory past its first page.
+    for i in 0..10_000 {
+        vec.push(&(i * 2)).unwrap();
+    }
+    assert_eq!(vec.len(), 10_000);
+    assert_eq!(vec.get(1234), Some(2468));
+    assert_eq!(vec.get(10_000), None);
+    vec.set(0, &7);
+    assert_eq!(vec.pop(), Some(19_998));
+
+    // The vector is found again as it was.
+    let mut vec = icfs::StableVec::<u64, _>::init(memory).unwrap();
+    assert_eq!(vec.len(), 9_999);
+    assert_eq!(vec.get(0), Some(7));
+    vec.truncate(3);
+    assert_eq!(vec.iter().collect::<Vec<_>>(), [7, 2, 4]);
+
+    // Several collections can share a memory through a memory manager.
+    let memory_manager = icfs::MemoryManager::init(icfs::VecMemory::new());
+    let mut bytes = icfs::StableVec::<[u8; 3], _>::init(memory_manager.get(icfs::MemoryId::new(0))).unwrap();
+    let mut numbers = icfs::StableVec::<i32, _>::init(memory_manager.get(icfs::MemoryId::new(1))).unwrap();
+    bytes.push(b"abc").unwrap();
+    numbers.push(&-1).unwrap();
+    assert_eq!(bytes.get(0), Some(*b"abc"));
+    assert_eq!(numbers.get(0), Some(-1));
+}
+
+#[update]
+fn test_stable_btree_map() {
+    let memory = icfs::VecMemory::new();
+    let mut map = icfs::StableBTreeMap::<u64, [u8; 8], _>::init(memory.clone()).unwrap();
+    assert!(map.is_empty());
+    assert_eq!(map.remove(&1), None);
+
+    // Enough entries for the tree to be several levels deep, inserted out of
+    // order.
+    for i in 0..1000u64 {
+        let key = i * 7919 % 1000;
+        assert_eq!(map.insert(key, key.to_le_bytes()).unwrap(), None);
+    }
+    assert_eq!(map.len(), 1000);
+    assert_eq!(map.get(&500), Some(500u64.to_le_bytes()));
+    assert_eq!(map.get(&1000), None);
+    assert_eq!(map.insert(500, [0; 8]).unwrap(), Some(500u64.to_le_bytes()));
+    assert_eq!(map.len(), 1000);
+    let keys: Vec<_> = map.iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+
+    // The map is found again as it was.
+    let mut map = icfs::StableBTreeMap::<u64, [u8; 8], _>::init(memory.clone()).unwrap();
+    assert_eq!(map.get(&500), Some([0; 8]));
+    for key in (0..1000).filter(|key| key % 3 != 0) {
+        assert_eq!(map.remove(&key), Some(if key == 500 { [0; 8] } else { key.to_le_bytes() }));
+    }
+    assert_eq!(map.remove(&1), None);
+    assert_eq!(map.len(), 334);
+    assert!(map.iter().map(|(key, _)| key).eq((0..1000).step_by(3)));
+
+    // Nodes freed by removing entries are reused.
+    let size = memory.size();
+    for key in 0..1000 {
+        map.remove(&key);
+    }
+    assert!(map.is_empty());
+    assert_eq!(map.iter().count(), 0);
+    for i in 0..1000u64 {
+        map.insert(i * 7919 % 1000, [1; 8]).unwrap();
+    }
+    assert_eq!(memory.size(), size);
+}
+
+// Runs the tests above natively, against the simulated stable memory provided
+// by the `mock` feature of icfs.
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    macro_rules! canister_tests {
+        ($($name:ident),* $(,)?) => {
+            $(
+                #[test]
+                fn $name() {
+                    icfs::mock::reset();
+                    super::$name();
+                }
+            )*
+        };
+    }
+
+    canister_tests!(
+        test_writer,
+        test_writer_vectored,
+        test_writer_seek,
+        test_writer_error,
+        test_reader,
+        test_reader_vectored,
+        test_read_to_end,
+        test_read_exact,
+        test_reader_error,
+        test_seek_past_end,
+        test_seek_before_0,
+        test_vec_memory,
+        test_region,
+        test_memory_manager,
+        test_read_at_write_at,
+        test_error_kinds,
+        test_growth_policy,
+        test_read_range,
+        test_chunks,
+        test_logical_len,
+        test_block_cache,
+        test_journal,
+        test_checksummed_region,
+        test_encrypted_memory,
+        test_compressed_memory,
+        test_dirty_pages,
+        test_image,
+        test_snapshot_memory,
+        test_instrumented_memory,
+        test_faulty_memory,
+        test_superblock,
+        test_stable_save,
+        test_allocator,
+        test_stable_vec,
+        test_stable_btree_map,
+    );
+
+    #[test]
+    fn test_grow_failure() {
+        icfs::mock::reset();
+        let mut stable_memory = icfs::StableMemory::default();
+
+        icfs::mock::fail_next_grows(1);
+        let error = stable_memory.write(&[0]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(
+            error.get_ref().unwrap().downcast_ref::<icfs::Error>(),
+            Some(&icfs::Error::GrowFailed {
+                requested: 1,
+                available: 0
+            })
+        );
+        assert_eq!(stable_memory.write(&[0]).unwrap(), 1);
+
+        icfs::mock::set_max_pages(1);
+        stable_memory.seek(SeekFrom::End(0)).unwrap();
+        assert!(stable_memory.write(&[0]).is_err());
+        assert_eq!(icfs::StableMemory::size(), 1);
+    }
+}